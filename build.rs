@@ -0,0 +1,5 @@
+// Migrations are embedded with `sqlx::migrate!`, so changing them has to
+// trigger a rebuild.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -1,3 +1,6 @@
+/* Baseline: the schema that used to be applied by hand, which databases
+ * created back then already have, hence the IF NOT EXISTS everywhere. */
+
 /* Only for SQLite
  * PRAGMA foreign_keys = ON; */
 
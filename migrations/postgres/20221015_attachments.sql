@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS "attachments" (
+    "id" SERIAL PRIMARY KEY,
+    "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "reference" TEXT NOT NULL,
+    "entry_id" INTEGER NOT NULL,
+    "filename" TEXT NOT NULL,
+    "content_type" TEXT NOT NULL,
+    "size" INTEGER NOT NULL,
+    "data" BYTEA NOT NULL,
+    FOREIGN KEY(reference) REFERENCES feeds(reference)
+);
+
+CREATE INDEX IF NOT EXISTS "attachmentsRef" ON "attachments" ("reference");
@@ -0,0 +1 @@
+ALTER TABLE "attachments" ADD COLUMN IF NOT EXISTS "content_id" TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "author_name" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "author_email" TEXT;
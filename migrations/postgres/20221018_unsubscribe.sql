@@ -0,0 +1,6 @@
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "unsubscribe_url" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "unsubscribe_mailto" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "unsubscribe_one_click" BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "feeds" ADD COLUMN IF NOT EXISTS "unsubscribe_url" TEXT;
+ALTER TABLE "feeds" ADD COLUMN IF NOT EXISTS "unsubscribe_mailto" TEXT;
+ALTER TABLE "feeds" ADD COLUMN IF NOT EXISTS "unsubscribe_one_click" BOOLEAN NOT NULL DEFAULT FALSE;
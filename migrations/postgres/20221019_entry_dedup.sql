@@ -0,0 +1,4 @@
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "message_id" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "digest" TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS "entriesMessageId" ON "entries" ("reference", "message_id");
+CREATE UNIQUE INDEX IF NOT EXISTS "entriesDigest" ON "entries" ("reference", "digest");
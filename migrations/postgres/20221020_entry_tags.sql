@@ -0,0 +1,2 @@
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "tag" TEXT;
+CREATE INDEX IF NOT EXISTS "entriesTag" ON "entries" ("reference", "tag");
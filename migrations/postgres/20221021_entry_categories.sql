@@ -0,0 +1,4 @@
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "list_id" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "list_name" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "list_post" TEXT;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "sender_domain" TEXT;
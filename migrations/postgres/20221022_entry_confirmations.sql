@@ -0,0 +1,2 @@
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "confirmation" BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "entries" ADD COLUMN IF NOT EXISTS "confirmation_url" TEXT;
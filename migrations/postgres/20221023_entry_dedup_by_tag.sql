@@ -0,0 +1,6 @@
+/* The same email sent to two tags of a feed is an entry for each of them,
+ * so duplicates are only looked for among entries with the same tag. */
+DROP INDEX IF EXISTS "entriesMessageId";
+DROP INDEX IF EXISTS "entriesDigest";
+CREATE UNIQUE INDEX IF NOT EXISTS "entriesTagMessageId" ON "entries" ("reference", (COALESCE("tag", '')), "message_id");
+CREATE UNIQUE INDEX IF NOT EXISTS "entriesTagDigest" ON "entries" ("reference", (COALESCE("tag", '')), "digest");
@@ -0,0 +1,23 @@
+/* Baseline, same as the Postgres one. SQLite databases have only ever been
+ * created by the migrator, so later migrations needn't be idempotent. */
+
+CREATE TABLE IF NOT EXISTS "feeds" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updated_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "reference" TEXT NOT NULL UNIQUE,
+    "title" TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "entries" (
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT,
+    "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "reference" TEXT NOT NULL,
+    "title" TEXT NOT NULL,
+    "author" TEXT NOT NULL,
+    "content" TEXT NOT NULL,
+    FOREIGN KEY(reference) REFERENCES feeds(reference)
+);
+
+CREATE INDEX IF NOT EXISTS "entriesRef" ON "entries" ("reference");
+CREATE INDEX IF NOT EXISTS "feedsRef" ON "entries" ("reference");
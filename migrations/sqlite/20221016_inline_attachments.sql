@@ -0,0 +1 @@
+ALTER TABLE "attachments" ADD COLUMN "content_id" TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE "entries" ADD COLUMN "author_name" TEXT;
+ALTER TABLE "entries" ADD COLUMN "author_email" TEXT;
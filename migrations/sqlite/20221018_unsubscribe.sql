@@ -0,0 +1,6 @@
+ALTER TABLE "entries" ADD COLUMN "unsubscribe_url" TEXT;
+ALTER TABLE "entries" ADD COLUMN "unsubscribe_mailto" TEXT;
+ALTER TABLE "entries" ADD COLUMN "unsubscribe_one_click" BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "feeds" ADD COLUMN "unsubscribe_url" TEXT;
+ALTER TABLE "feeds" ADD COLUMN "unsubscribe_mailto" TEXT;
+ALTER TABLE "feeds" ADD COLUMN "unsubscribe_one_click" BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+ALTER TABLE "entries" ADD COLUMN "list_id" TEXT;
+ALTER TABLE "entries" ADD COLUMN "list_name" TEXT;
+ALTER TABLE "entries" ADD COLUMN "list_post" TEXT;
+ALTER TABLE "entries" ADD COLUMN "sender_domain" TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE "entries" ADD COLUMN "confirmation" BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "entries" ADD COLUMN "confirmation_url" TEXT;
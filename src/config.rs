@@ -0,0 +1,751 @@
+//! # Runtime configuration
+//!
+//! Every setting can come from a command line flag, an environment variable
+//! (a `.env` file is loaded into the environment at startup) or a TOML
+//! config file, in that order of precedence, before falling back to the
+//! defaults below. The resulting [`Config`] is validated once at startup and
+//! then shared by the web application and the SMTP server.
+//!
+//! ```toml
+//! web_url = "https://ktnrs.com"
+//! email_domain = "ktnrs.com"
+//! http_addr = "0.0.0.0:8080"
+//! smtp_addr = "0.0.0.0:2525"
+//! database_url = "postgresql://localhost/ktn"
+//! db_pool_size = 20
+//! db_auto_migrate = true
+//! static_folder = "/usr/local/share/ktn/static"
+//! smtp_max_message_size = 10485760
+//! smtp_tls_cert = "/etc/ktn/cert.pem"
+//! smtp_tls_key = "/etc/ktn/key.pem"
+//! smtp_require_tls = false
+//! smtp_command_timeout = 300
+//! smtp_data_timeout = 600
+//! smtp_max_sessions = 100
+//! smtp_connections_per_minute = 30
+//! smtp_messages_per_minute = 60
+//! shutdown_timeout = 25
+//! attachment_max_size = 5242880
+//! attachment_types = ["application/pdf", "audio/*", "image/*", "video/*"]
+//! html_extra_tags = []
+//! html_inline_styles = true
+//! strip_trackers = true
+//! feed_placeholder_titles = ["Untitled", "Newsletter"]
+//! ```
+
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::database::Backend;
+
+/// Config file read when `--config` isn't given, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "ktn.toml";
+
+const DEFAULT_HTTP_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_SMTP_ADDR: &str = "0.0.0.0:2525";
+const DEFAULT_DB_POOL_SIZE: u32 = 20;
+const DEFAULT_STATIC_FOLDER: &str = "static";
+const DEFAULT_SMTP_MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+// RFC 5321 section 4.5.3.2 suggests 5 and 10 minutes respectively
+const DEFAULT_SMTP_COMMAND_TIMEOUT: u64 = 300;
+const DEFAULT_SMTP_DATA_TIMEOUT: u64 = 600;
+const DEFAULT_SMTP_MAX_SESSIONS: usize = 100;
+const DEFAULT_SMTP_CONNECTIONS_PER_MINUTE: u32 = 30;
+const DEFAULT_SMTP_MESSAGES_PER_MINUTE: u32 = 60;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 25;
+const DEFAULT_ATTACHMENT_MAX_SIZE: usize = 5 * 1024 * 1024;
+const DEFAULT_ATTACHMENT_TYPES: &[&str] =
+    &["application/pdf", "audio/*", "image/*", "video/*"];
+const DEFAULT_FEED_PLACEHOLDER_TITLES: &[&str] = &["Untitled", "Newsletter"];
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Couldn't read config file {path:?} ({source})")]
+    CouldNotRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Couldn't parse config file {path:?} ({source})")]
+    CouldNotParse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("Missing required setting `{0}`")]
+    Missing(&'static str),
+    #[error("Invalid value for setting `{name}`: {reason}")]
+    Invalid { name: &'static str, reason: String },
+}
+
+/// What to run instead of the HTTP and SMTP servers.
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum Command {
+    /// Apply the pending database migrations and exit
+    Migrate {
+        /// Only list the pending migrations, checking those already applied
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Partial settings as given by a single source. Every field is optional so
+/// sources can be layered on top of each other with [`Settings::or`].
+#[derive(Debug, Default, Deserialize, Parser)]
+#[command(name = "ktn", version, about)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// TOML config file, defaults to `ktn.toml` if present
+    #[arg(short, long, env = "KTN_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
+    /// Public URL the web application is served from
+    #[arg(long, env = "WEB_URL")]
+    pub web_url: Option<String>,
+
+    /// Domain the newsletter inboxes live under
+    #[arg(long, env = "EMAIL_DOMAIN")]
+    pub email_domain: Option<String>,
+
+    /// Address and port the HTTP server binds to
+    #[arg(long, env = "HTTP_ADDR")]
+    pub http_addr: Option<SocketAddr>,
+
+    /// Address and port the SMTP server binds to
+    #[arg(long, env = "SMTP_ADDR")]
+    pub smtp_addr: Option<SocketAddr>,
+
+    /// Postgres (`postgres://`) or SQLite (`sqlite:`) connection string
+    #[arg(long, env = "DATABASE_URL")]
+    pub database_url: Option<String>,
+
+    /// Maximum number of connections in the database pool
+    #[arg(long, env = "DB_POOL_SIZE")]
+    pub db_pool_size: Option<u32>,
+
+    /// Apply the pending database migrations on startup
+    #[arg(long, env = "DB_AUTO_MIGRATE")]
+    pub db_auto_migrate: Option<bool>,
+
+    /// Folder the static assets (favicons, CSS) are served from
+    #[arg(long, env = "STATIC_FOLDER")]
+    pub static_folder: Option<PathBuf>,
+
+    /// Largest email, in bytes, the SMTP server accepts
+    #[arg(long, env = "SMTP_MAX_MESSAGE_SIZE")]
+    pub smtp_max_message_size: Option<usize>,
+
+    /// PEM certificate chain offered through STARTTLS
+    #[arg(long, env = "SMTP_TLS_CERT")]
+    pub smtp_tls_cert: Option<PathBuf>,
+
+    /// PEM private key for the STARTTLS certificate
+    #[arg(long, env = "SMTP_TLS_KEY")]
+    pub smtp_tls_key: Option<PathBuf>,
+
+    /// Refuse MAIL FROM until the client has issued STARTTLS
+    #[arg(long, env = "SMTP_REQUIRE_TLS")]
+    pub smtp_require_tls: Option<bool>,
+
+    /// Seconds to wait for the next SMTP command before hanging up
+    #[arg(long, env = "SMTP_COMMAND_TIMEOUT")]
+    pub smtp_command_timeout: Option<u64>,
+
+    /// Seconds to wait for the whole DATA payload before hanging up
+    #[arg(long, env = "SMTP_DATA_TIMEOUT")]
+    pub smtp_data_timeout: Option<u64>,
+
+    /// Maximum number of SMTP sessions served at once
+    #[arg(long, env = "SMTP_MAX_SESSIONS")]
+    pub smtp_max_sessions: Option<usize>,
+
+    /// SMTP connections allowed per minute from a single IP address
+    #[arg(long, env = "SMTP_CONNECTIONS_PER_MINUTE")]
+    pub smtp_connections_per_minute: Option<u32>,
+
+    /// Emails accepted per minute from a single IP address
+    #[arg(long, env = "SMTP_MESSAGES_PER_MINUTE")]
+    pub smtp_messages_per_minute: Option<u32>,
+
+    /// Seconds given to open connections to wrap up when shutting down
+    #[arg(long, env = "SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+
+    /// Largest attachment, in bytes, kept from an email
+    #[arg(long, env = "ATTACHMENT_MAX_SIZE")]
+    pub attachment_max_size: Option<usize>,
+
+    /// Comma separated content types of the attachments kept, e.g.
+    /// `application/pdf,audio/*`, none at all if empty
+    #[arg(long, env = "ATTACHMENT_TYPES", value_delimiter = ',')]
+    pub attachment_types: Option<Vec<String>>,
+
+    /// Comma separated tags to keep in newsletters' HTML on top of the
+    /// default allowlist, e.g. `video,audio`
+    #[arg(long, env = "HTML_EXTRA_TAGS", value_delimiter = ',')]
+    pub html_extra_tags: Option<Vec<String>>,
+
+    /// Keep the inline `style` attributes of newsletters' HTML
+    #[arg(long, env = "HTML_INLINE_STYLES")]
+    pub html_inline_styles: Option<bool>,
+
+    /// Drop tracking pixels and unwrap tracking redirects in newsletters
+    #[arg(long, env = "STRIP_TRACKERS")]
+    pub strip_trackers: Option<bool>,
+
+    /// Comma separated feed titles to replace with the List-Id description
+    /// of the first newsletter that has one, never renaming feeds if empty
+    #[arg(long, env = "FEED_PLACEHOLDER_TITLES", value_delimiter = ',')]
+    pub feed_placeholder_titles: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// Reads [`Settings`] from a TOML file.
+    pub fn from_file(path: &Path) -> Result<Settings, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| {
+            ConfigError::CouldNotRead {
+                path: path.to_owned(),
+                source,
+            }
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::CouldNotParse {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Fills in every setting missing from `self` with the one in `fallback`.
+    pub fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            config: self.config.or(fallback.config),
+            command: self.command.or(fallback.command),
+            web_url: self.web_url.or(fallback.web_url),
+            email_domain: self.email_domain.or(fallback.email_domain),
+            http_addr: self.http_addr.or(fallback.http_addr),
+            smtp_addr: self.smtp_addr.or(fallback.smtp_addr),
+            database_url: self.database_url.or(fallback.database_url),
+            db_pool_size: self.db_pool_size.or(fallback.db_pool_size),
+            db_auto_migrate: self.db_auto_migrate.or(fallback.db_auto_migrate),
+            static_folder: self.static_folder.or(fallback.static_folder),
+            smtp_max_message_size: self
+                .smtp_max_message_size
+                .or(fallback.smtp_max_message_size),
+            smtp_tls_cert: self.smtp_tls_cert.or(fallback.smtp_tls_cert),
+            smtp_tls_key: self.smtp_tls_key.or(fallback.smtp_tls_key),
+            smtp_require_tls: self
+                .smtp_require_tls
+                .or(fallback.smtp_require_tls),
+            smtp_command_timeout: self
+                .smtp_command_timeout
+                .or(fallback.smtp_command_timeout),
+            smtp_data_timeout: self
+                .smtp_data_timeout
+                .or(fallback.smtp_data_timeout),
+            smtp_max_sessions: self
+                .smtp_max_sessions
+                .or(fallback.smtp_max_sessions),
+            smtp_connections_per_minute: self
+                .smtp_connections_per_minute
+                .or(fallback.smtp_connections_per_minute),
+            smtp_messages_per_minute: self
+                .smtp_messages_per_minute
+                .or(fallback.smtp_messages_per_minute),
+            shutdown_timeout: self
+                .shutdown_timeout
+                .or(fallback.shutdown_timeout),
+            attachment_max_size: self
+                .attachment_max_size
+                .or(fallback.attachment_max_size),
+            attachment_types: self
+                .attachment_types
+                .or(fallback.attachment_types),
+            html_extra_tags: self.html_extra_tags.or(fallback.html_extra_tags),
+            html_inline_styles: self
+                .html_inline_styles
+                .or(fallback.html_inline_styles),
+            strip_trackers: self.strip_trackers.or(fallback.strip_trackers),
+            feed_placeholder_titles: self
+                .feed_placeholder_titles
+                .or(fallback.feed_placeholder_titles),
+        }
+    }
+}
+
+/// Validated, complete application configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Set when running a one-off command rather than the servers
+    pub command: Option<Command>,
+    /// Public URL without a trailing slash, e.g. `https://ktnrs.com`
+    pub web_url: String,
+    pub email_domain: String,
+    pub http_addr: SocketAddr,
+    pub smtp_addr: SocketAddr,
+    pub database_url: String,
+    pub db_pool_size: u32,
+    pub db_auto_migrate: bool,
+    pub static_folder: PathBuf,
+    /// Advertised through the EHLO `SIZE` extension and enforced on DATA
+    pub smtp_max_message_size: usize,
+    /// Certificate and key paths, STARTTLS is only offered if they're set
+    pub smtp_tls: Option<(PathBuf, PathBuf)>,
+    pub smtp_require_tls: bool,
+    pub smtp_command_timeout: Duration,
+    pub smtp_data_timeout: Duration,
+    pub smtp_max_sessions: usize,
+    /// Size and per-minute refill of each IP address' token buckets
+    pub smtp_connections_per_minute: u32,
+    pub smtp_messages_per_minute: u32,
+    /// How long in-flight sessions get to finish once a shutdown begins
+    pub shutdown_timeout: Duration,
+    pub attachment_max_size: usize,
+    /// Either full content types or whole families of them, like `audio/*`
+    pub attachment_types: Vec<String>,
+    /// Allowed in newsletters' HTML along with the default allowlist
+    pub html_extra_tags: Vec<String>,
+    pub html_inline_styles: bool,
+    pub strip_trackers: bool,
+    /// Titles of feeds still waiting to be named after their newsletter
+    pub feed_placeholder_titles: Vec<String>,
+}
+
+/// Refuses zero for settings that need to be at least 1.
+fn at_least_one<T>(name: &'static str, value: T) -> Result<T, ConfigError>
+where
+    T: Default + PartialEq,
+{
+    match value == T::default() {
+        true => Err(ConfigError::Invalid {
+            name,
+            reason: "must be at least 1".to_owned(),
+        }),
+        false => Ok(value),
+    }
+}
+
+impl Config {
+    /// Loads the [`Config`] from the command line, the environment, and the
+    /// config file, in that order of precedence.
+    pub fn load() -> Result<Config, ConfigError> {
+        dotenv::dotenv().ok();
+        Config::from_cli(Settings::parse())
+    }
+
+    /// Completes the [`Settings`] parsed from the command line (and
+    /// environment) with those in the config file they point at, if any.
+    fn from_cli(cli: Settings) -> Result<Config, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => Settings::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => {
+                Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Settings::default(),
+        };
+
+        Config::try_from(cli.or(file))
+    }
+}
+
+impl TryFrom<Settings> for Config {
+    type Error = ConfigError;
+
+    fn try_from(settings: Settings) -> Result<Self, Self::Error> {
+        let web_url =
+            settings.web_url.ok_or(ConfigError::Missing("web_url"))?;
+        let web_url = web_url.trim_end_matches('/').to_owned();
+        if !(web_url.starts_with("http://") || web_url.starts_with("https://"))
+        {
+            return Err(ConfigError::Invalid {
+                name: "web_url",
+                reason: format!("{} isn't an http(s) URL", web_url),
+            });
+        }
+
+        let email_domain = settings
+            .email_domain
+            .ok_or(ConfigError::Missing("email_domain"))?;
+        if email_domain.is_empty()
+            || email_domain.contains(|c: char| c == '@' || c.is_whitespace())
+        {
+            return Err(ConfigError::Invalid {
+                name: "email_domain",
+                reason: format!("\"{}\" isn't a domain name", email_domain),
+            });
+        }
+
+        let database_url = settings
+            .database_url
+            .ok_or(ConfigError::Missing("database_url"))?;
+        if Backend::from_url(&database_url).is_none() {
+            return Err(ConfigError::Invalid {
+                name: "database_url",
+                reason: "only postgres:// and sqlite: URLs are supported"
+                    .to_owned(),
+            });
+        }
+
+        let db_pool_size = at_least_one(
+            "db_pool_size",
+            settings.db_pool_size.unwrap_or(DEFAULT_DB_POOL_SIZE),
+        )?;
+
+        let static_folder = settings
+            .static_folder
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATIC_FOLDER));
+        if !static_folder.is_dir() {
+            return Err(ConfigError::Invalid {
+                name: "static_folder",
+                reason: format!("{:?} isn't a folder", static_folder),
+            });
+        }
+
+        let smtp_max_message_size = at_least_one(
+            "smtp_max_message_size",
+            settings
+                .smtp_max_message_size
+                .unwrap_or(DEFAULT_SMTP_MAX_MESSAGE_SIZE),
+        )?;
+
+        let smtp_tls = match (settings.smtp_tls_cert, settings.smtp_tls_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid {
+                    name: "smtp_tls_cert",
+                    reason: "needs to be set along with smtp_tls_key"
+                        .to_owned(),
+                })
+            }
+        };
+
+        let smtp_require_tls = settings.smtp_require_tls.unwrap_or(false);
+        if smtp_require_tls && smtp_tls.is_none() {
+            return Err(ConfigError::Invalid {
+                name: "smtp_require_tls",
+                reason: "needs smtp_tls_cert and smtp_tls_key".to_owned(),
+            });
+        }
+
+        let attachment_types = settings.attachment_types.unwrap_or_else(|| {
+            DEFAULT_ATTACHMENT_TYPES
+                .iter()
+                .map(|kind| kind.to_string())
+                .collect()
+        });
+        for kind in &attachment_types {
+            let valid = match kind.split_once('/') {
+                Some((family, subtype)) => {
+                    !family.is_empty()
+                        && !subtype.is_empty()
+                        && !kind.contains(|c: char| c.is_whitespace())
+                        && !subtype.contains('/')
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(ConfigError::Invalid {
+                    name: "attachment_types",
+                    reason: format!("\"{}\" isn't a content type", kind),
+                });
+            }
+        }
+
+        let html_extra_tags = settings.html_extra_tags.unwrap_or_default();
+        for tag in &html_extra_tags {
+            if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(ConfigError::Invalid {
+                    name: "html_extra_tags",
+                    reason: format!("\"{}\" isn't a tag name", tag),
+                });
+            }
+        }
+
+        let feed_placeholder_titles =
+            settings.feed_placeholder_titles.unwrap_or_else(|| {
+                DEFAULT_FEED_PLACEHOLDER_TITLES
+                    .iter()
+                    .map(|title| title.to_string())
+                    .collect()
+            });
+        if feed_placeholder_titles
+            .iter()
+            .any(|title| title.trim().is_empty())
+        {
+            return Err(ConfigError::Invalid {
+                name: "feed_placeholder_titles",
+                reason: "titles can't be blank".to_owned(),
+            });
+        }
+
+        Ok(Config {
+            command: settings.command,
+            web_url,
+            email_domain,
+            http_addr: settings
+                .http_addr
+                .unwrap_or_else(|| DEFAULT_HTTP_ADDR.parse().unwrap()),
+            smtp_addr: settings
+                .smtp_addr
+                .unwrap_or_else(|| DEFAULT_SMTP_ADDR.parse().unwrap()),
+            database_url,
+            db_pool_size,
+            db_auto_migrate: settings.db_auto_migrate.unwrap_or(true),
+            static_folder,
+            smtp_max_message_size,
+            smtp_tls,
+            smtp_require_tls,
+            smtp_command_timeout: Duration::from_secs(at_least_one(
+                "smtp_command_timeout",
+                settings
+                    .smtp_command_timeout
+                    .unwrap_or(DEFAULT_SMTP_COMMAND_TIMEOUT),
+            )?),
+            smtp_data_timeout: Duration::from_secs(at_least_one(
+                "smtp_data_timeout",
+                settings
+                    .smtp_data_timeout
+                    .unwrap_or(DEFAULT_SMTP_DATA_TIMEOUT),
+            )?),
+            smtp_max_sessions: at_least_one(
+                "smtp_max_sessions",
+                settings
+                    .smtp_max_sessions
+                    .unwrap_or(DEFAULT_SMTP_MAX_SESSIONS),
+            )?,
+            smtp_connections_per_minute: at_least_one(
+                "smtp_connections_per_minute",
+                settings
+                    .smtp_connections_per_minute
+                    .unwrap_or(DEFAULT_SMTP_CONNECTIONS_PER_MINUTE),
+            )?,
+            smtp_messages_per_minute: at_least_one(
+                "smtp_messages_per_minute",
+                settings
+                    .smtp_messages_per_minute
+                    .unwrap_or(DEFAULT_SMTP_MESSAGES_PER_MINUTE),
+            )?,
+            shutdown_timeout: Duration::from_secs(
+                settings
+                    .shutdown_timeout
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            ),
+            attachment_max_size: at_least_one(
+                "attachment_max_size",
+                settings
+                    .attachment_max_size
+                    .unwrap_or(DEFAULT_ATTACHMENT_MAX_SIZE),
+            )?,
+            attachment_types,
+            html_extra_tags,
+            html_inline_styles: settings.html_inline_styles.unwrap_or(true),
+            strip_trackers: settings.strip_trackers.unwrap_or(true),
+            feed_placeholder_titles,
+        })
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// A valid [`Config`] for tests elsewhere in the crate.
+    pub fn for_tests() -> Config {
+        Config::try_from(Settings {
+            web_url: Some("https://ktnrs.com".to_owned()),
+            email_domain: Some("ktnrs.com".to_owned()),
+            database_url: Some("postgresql://localhost".to_owned()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Config, ConfigError, Settings};
+    use clap::Parser;
+
+    fn required() -> Settings {
+        Settings {
+            web_url: Some("https://ktnrs.com/".to_owned()),
+            email_domain: Some("ktnrs.com".to_owned()),
+            database_url: Some("postgresql://localhost".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn defaults_fill_in_optional_settings() {
+        let config = Config::try_from(required()).unwrap();
+
+        assert_eq!(config.web_url, "https://ktnrs.com");
+        assert_eq!(config.http_addr.to_string(), "0.0.0.0:8080");
+        assert_eq!(config.smtp_addr.to_string(), "0.0.0.0:2525");
+        assert_eq!(config.db_pool_size, 20);
+    }
+
+    #[test]
+    fn sqlite_database_urls() {
+        for database_url in ["sqlite:///data/ktn.db", "sqlite::memory:"] {
+            let config = Config::try_from(Settings {
+                database_url: Some(database_url.to_owned()),
+                ..required()
+            })
+            .unwrap();
+
+            assert_eq!(config.database_url, database_url);
+        }
+    }
+
+    #[test]
+    fn cli_flags_take_precedence_over_file() {
+        let cli = Settings::try_parse_from([
+            "ktn",
+            "--email-domain",
+            "cli.example",
+            "--smtp-addr",
+            "127.0.0.1:25",
+        ])
+        .unwrap();
+        let file: Settings = toml::from_str(
+            r#"
+            email_domain = "file.example"
+            db_pool_size = 5
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::try_from(cli.or(file).or(required())).unwrap();
+
+        assert_eq!(config.email_domain, "cli.example");
+        assert_eq!(config.smtp_addr.to_string(), "127.0.0.1:25");
+        assert_eq!(config.db_pool_size, 5);
+    }
+
+    #[test]
+    fn attachment_types_list() {
+        let cli = Settings::try_parse_from([
+            "ktn",
+            "--attachment-types",
+            "application/pdf,audio/*",
+        ])
+        .unwrap();
+        let config = Config::try_from(cli.or(required())).unwrap();
+        assert_eq!(config.attachment_types, ["application/pdf", "audio/*"]);
+
+        let file: Settings = toml::from_str("attachment_types = []").unwrap();
+        let config = Config::try_from(file.or(required())).unwrap();
+        assert!(config.attachment_types.is_empty());
+    }
+
+    #[test]
+    fn migrate_subcommand() {
+        let cli =
+            Settings::try_parse_from(["ktn", "migrate", "--dry-run"]).unwrap();
+        let config = Config::try_from(cli.or(required())).unwrap();
+        assert_eq!(config.command, Some(Command::Migrate { dry_run: true }));
+
+        let cli = Settings::try_parse_from(["ktn"]).unwrap();
+        let config = Config::try_from(cli.or(required())).unwrap();
+        assert_eq!(config.command, None);
+        assert!(config.db_auto_migrate);
+    }
+
+    #[test]
+    fn unknown_file_settings_are_rejected() {
+        assert!(toml::from_str::<Settings>("web_ulr = \"typo\"").is_err());
+    }
+
+    #[test]
+    fn missing_required_setting() {
+        let settings = Settings {
+            web_url: None,
+            ..required()
+        };
+
+        assert!(matches!(
+            Config::try_from(settings),
+            Err(ConfigError::Missing("web_url"))
+        ));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let cases = [
+            Settings {
+                web_url: Some("ktnrs.com".to_owned()),
+                ..required()
+            },
+            Settings {
+                email_domain: Some("inbox@ktnrs.com".to_owned()),
+                ..required()
+            },
+            Settings {
+                database_url: Some("mysql://localhost".to_owned()),
+                ..required()
+            },
+            Settings {
+                db_pool_size: Some(0),
+                ..required()
+            },
+            Settings {
+                static_folder: Some("does/not/exist".into()),
+                ..required()
+            },
+            Settings {
+                smtp_max_message_size: Some(0),
+                ..required()
+            },
+            Settings {
+                smtp_tls_key: Some("key.pem".into()),
+                ..required()
+            },
+            Settings {
+                smtp_require_tls: Some(true),
+                ..required()
+            },
+            Settings {
+                smtp_command_timeout: Some(0),
+                ..required()
+            },
+            Settings {
+                smtp_max_sessions: Some(0),
+                ..required()
+            },
+            Settings {
+                smtp_messages_per_minute: Some(0),
+                ..required()
+            },
+            Settings {
+                attachment_max_size: Some(0),
+                ..required()
+            },
+            Settings {
+                attachment_types: Some(vec!["pdf".to_owned()]),
+                ..required()
+            },
+            Settings {
+                html_extra_tags: Some(vec!["<video>".to_owned()]),
+                ..required()
+            },
+            Settings {
+                feed_placeholder_titles: Some(vec![" ".to_owned()]),
+                ..required()
+            },
+        ];
+
+        for settings in cases {
+            assert!(matches!(
+                Config::try_from(settings),
+                Err(ConfigError::Invalid { .. })
+            ));
+        }
+    }
+}
@@ -1,8 +1,26 @@
-//!  Thread-safe SQLite connection pool instatiation
-use sqlx::{postgres::PgPoolOptions, Pool as SqlxPool};
+//! Thread-safe connection pool instantiation, for either of the supported
+//! backends: Postgres or SQLite, picked by the scheme of the `database_url`.
+//!
+//! Along with the versioned migrations in the `migrations` folder, one set
+//! per backend, which are embedded in the binary and applied in order, each
+//! of them only once. The checksum of those already applied is checked
+//! against their file, so editing a migration after the fact is caught
+//! rather than ignored.
+//!
+//! The first Postgres one is the schema that used to be applied by hand,
+//! and every one of them is idempotent, so databases created that way are
+//! migrated like any other.
+use sqlx::any::{AnyConnectOptions, AnyKind, AnyPoolOptions};
+use sqlx::migrate::{Migrate, MigrateError, Migration, Migrator};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
 use thiserror::Error;
 
-pub type Pool = SqlxPool<sqlx::Postgres>;
+use crate::config::Config;
+
+/// A pool of connections to whichever [`Backend`] the `database_url` is for.
+pub type Pool = sqlx::AnyPool;
 
 #[derive(Debug, Error)]
 pub enum DatabaseError {
@@ -10,13 +28,202 @@ pub enum DatabaseError {
     CouldNotInsert,
 }
 
-pub async fn get_db_pool() -> Result<Pool, sqlx::Error> {
-    let pool = PgPoolOptions::new()
-        .connect_timeout(std::time::Duration::new(3, 0))
-        .max_connections(20)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
-        .await
-        .expect("Couldn't connect to the DB");
+/// The databases feeds can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Postgres,
+    Sqlite,
+}
+
+impl Backend {
+    /// Tells which backend a connection string is for, by its scheme.
+    pub fn from_url(url: &str) -> Option<Backend> {
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Some(Backend::Postgres)
+        } else if url.starts_with("sqlite:") {
+            Some(Backend::Sqlite)
+        } else {
+            None
+        }
+    }
+
+    /// The backend a pool is connected to.
+    pub fn of(pool: &Pool) -> Backend {
+        match pool.any_kind() {
+            AnyKind::Postgres => Backend::Postgres,
+            AnyKind::Sqlite => Backend::Sqlite,
+        }
+    }
+
+    /// The migrations for this backend's flavour of SQL.
+    pub fn migrator(self) -> &'static Migrator {
+        match self {
+            Backend::Postgres => &POSTGRES_MIGRATOR,
+            Backend::Sqlite => &SQLITE_MIGRATOR,
+        }
+    }
+}
+
+pub async fn get_db_pool(config: &Config) -> Result<Pool, sqlx::Error> {
+    connect(&config.database_url, config.db_pool_size).await
+}
+
+/// Connects to the database at `url`, creating it first if it's a SQLite
+/// file that isn't there yet.
+pub async fn connect(
+    url: &str,
+    max_connections: u32,
+) -> Result<Pool, sqlx::Error> {
+    let mut options = AnyConnectOptions::from_str(url)?;
+    let mut pool_options =
+        AnyPoolOptions::new().connect_timeout(Duration::new(3, 0));
+    if let Some(sqlite) = options.as_sqlite_mut() {
+        *sqlite = sqlite.clone().create_if_missing(true);
+    }
+
+    // Every connection to an in-memory SQLite database gets a database of
+    // its own, so there must be just the one, and it must be kept open
+    if url.contains(":memory:") || url.contains("mode=memory") {
+        pool_options = pool_options
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+    } else {
+        pool_options = pool_options.max_connections(max_connections);
+    }
+
+    pool_options.connect_with(options).await
+}
+
+/// Every Postgres migration, as found in `migrations/postgres` at build time.
+pub static POSTGRES_MIGRATOR: Migrator =
+    sqlx::migrate!("./migrations/postgres");
+
+/// Every SQLite migration, as found in `migrations/sqlite` at build time.
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Returns the migrations yet to be applied, in the order they will be,
+/// checking those already applied haven't changed since. Nothing is
+/// written to the database.
+pub async fn pending_migrations(
+    pool: &Pool,
+) -> Result<Vec<&'static Migration>, MigrateError> {
+    // The migrations table may not be there yet, and creating it is as far
+    // as a dry run should go, so that's rolled back
+    let mut tx = pool.begin().await?;
+    tx.ensure_migrations_table().await?;
+    if let Some(version) = tx.dirty_version().await? {
+        return Err(MigrateError::Dirty(version));
+    }
+    let applied: HashMap<i64, _> = tx
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect();
+    tx.rollback().await?;
+
+    let migrator = Backend::of(pool).migrator();
+    for version in applied.keys() {
+        if !migrator
+            .iter()
+            .any(|migration| migration.version == *version)
+        {
+            return Err(MigrateError::VersionMissing(*version));
+        }
+    }
+
+    let mut pending = vec![];
+    for migration in migrator.iter() {
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version))
+            }
+            Some(_) => {}
+            None => pending.push(migration),
+        }
+    }
+
+    Ok(pending)
+}
+
+/// Applies every pending migration, returning those that were.
+pub async fn migrate(
+    pool: &Pool,
+) -> Result<Vec<&'static Migration>, MigrateError> {
+    let pending = pending_migrations(pool).await?;
+    Backend::of(pool).migrator().run(pool).await?;
+
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, POSTGRES_MIGRATOR, SQLITE_MIGRATOR};
+
+    #[test]
+    fn backend_from_url() {
+        assert_eq!(
+            Backend::from_url("postgres://localhost/ktn"),
+            Some(Backend::Postgres)
+        );
+        assert_eq!(
+            Backend::from_url("postgresql://localhost/ktn"),
+            Some(Backend::Postgres)
+        );
+        assert_eq!(
+            Backend::from_url("sqlite:///data/ktn.db"),
+            Some(Backend::Sqlite)
+        );
+        assert_eq!(Backend::from_url("sqlite::memory:"), Some(Backend::Sqlite));
+        assert_eq!(Backend::from_url("mysql://localhost/ktn"), None);
+        assert_eq!(Backend::from_url("ktn.db"), None);
+    }
+
+    #[test]
+    fn migrations_are_embedded_in_order() {
+        for migrator in [&POSTGRES_MIGRATOR, &SQLITE_MIGRATOR] {
+            let versions: Vec<i64> =
+                migrator.iter().map(|migration| migration.version).collect();
+
+            assert_eq!(versions.first(), Some(&20220327));
+            assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(
+                migrator.iter().next().unwrap().description,
+                "initial schema"
+            );
+        }
+    }
+
+    #[test]
+    fn migrations_match_across_backends() {
+        let versions = |migrator: &super::Migrator| -> Vec<(i64, String)> {
+            migrator
+                .iter()
+                .map(|m| (m.version, m.description.to_string()))
+                .collect()
+        };
+
+        assert_eq!(versions(&POSTGRES_MIGRATOR), versions(&SQLITE_MIGRATOR));
+    }
 
-    Ok(pool)
+    #[test]
+    fn postgres_migrations_are_idempotent() {
+        // So they can run against databases created by the old script, or
+        // whose indexes were dropped by hand
+        for migration in POSTGRES_MIGRATOR.iter() {
+            for statement in migration.sql.split(';') {
+                let statement = statement.trim().to_uppercase();
+                assert!(
+                    statement.is_empty()
+                        || statement.starts_with("/*")
+                        || statement.contains("IF NOT EXISTS")
+                        || statement.contains("IF EXISTS"),
+                    "{}: {}",
+                    migration.description,
+                    statement
+                );
+            }
+        }
+    }
 }
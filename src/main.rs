@@ -1,47 +1,146 @@
+mod config;
 mod database;
 mod models;
+mod shutdown;
 mod smtp;
 mod time;
-mod vars;
 mod web;
 
 use ktn::tracing::setup_tracing;
 use std::error::Error;
-use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio::signal;
-use tracing::error;
+use tokio::time::timeout;
+use tracing::{error, info, warn};
 
-use crate::database::get_db_pool;
+use crate::config::{Command, Config};
+use crate::database::{get_db_pool, migrate, pending_migrations};
 use crate::smtp::app::serve_smtp;
+use crate::smtp::tls::tls_acceptor;
 use crate::web::build_app;
+use crate::web::unsubscribe::HttpOneClick;
+
+/// Applies the pending migrations, logging each of them.
+async fn run_migrations(pool: &database::Pool) -> Result<(), Box<dyn Error>> {
+    let applied = migrate(pool).await.map_err(|e| {
+        error!("Couldn't migrate the database: {}", e);
+        e
+    })?;
+    for migration in &applied {
+        info!(
+            "Applied migration {} ({})",
+            migration.version, migration.description
+        );
+    }
+
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     setup_tracing();
 
-    let pool = get_db_pool().await?;
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
 
-    let http_addr: SocketAddrV4 = "0.0.0.0:8080".parse().unwrap();
-    let http_addr = SocketAddr::from(http_addr);
+    let tls = match tls_acceptor(&config) {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Invalid SMTP TLS configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
 
-    let http_listener = axum::Server::bind(&http_addr);
-    let http_app = build_app(pool.clone());
-    let smtp_listener = TcpListener::bind("0.0.0.0:2525").await.unwrap();
+    let pool = get_db_pool(&config).await?;
 
-    // Serve HTTP and SMTP, and end the program whenever either of those
-    // futures returns (fails) or if a system interrupt is received.
-    tokio::select! {
-        _ = http_listener.serve(http_app) => {
+    match config.command {
+        Some(Command::Migrate { dry_run: true }) => {
+            let pending = pending_migrations(&pool).await?;
+            for migration in &pending {
+                info!(
+                    "Pending migration {} ({})",
+                    migration.version, migration.description
+                );
+            }
+            info!("{} pending migrations", pending.len());
+            return Ok(());
+        }
+        Some(Command::Migrate { dry_run: false }) => {
+            run_migrations(&pool).await?;
+            return Ok(());
+        }
+        None if config.db_auto_migrate => run_migrations(&pool).await?,
+        None => {}
+    }
+    let (trigger, shutdown) = shutdown::channel();
+
+    let one_click = HttpOneClick::new()?;
+    let http_app = build_app(pool.clone(), config.clone(), one_click);
+    let http_shutdown = shutdown.clone();
+    let mut http = tokio::spawn(
+        axum::Server::bind(&config.http_addr)
+            .serve(http_app)
+            .with_graceful_shutdown(
+                async move { http_shutdown.triggered().await },
+            ),
+    );
+    let smtp_listener = TcpListener::bind(config.smtp_addr).await.unwrap();
+    let mut smtp = tokio::spawn(serve_smtp(
+        smtp_listener,
+        pool.clone(),
+        config.clone(),
+        tls,
+        shutdown,
+    ));
+
+    // Serve HTTP and SMTP until either of those fails or a SIGINT/SIGTERM
+    // is received, then give whatever's in flight a chance to finish.
+    let (http_done, smtp_done) = tokio::select! {
+        _ = &mut http => {
             error!("HTTP service exited prematurely");
+            (true, false)
         }
-        _ = serve_smtp(&smtp_listener, pool.clone()) => {
+        _ = &mut smtp => {
             error!("SMTP service exited prematurely");
+            (false, true)
+        }
+        _ = shutdown::signal() => {
+            info!("Shutdown signal received, draining connections...");
+            (false, false)
         }
-        _ = signal::ctrl_c() => {
-            error!("SIGINT Received, shutting down...");
+    };
+
+    trigger.trigger();
+    let drained = timeout(config.shutdown_timeout, async {
+        if !http_done {
+            let _ = (&mut http).await;
+        }
+        if !smtp_done {
+            let _ = (&mut smtp).await;
         }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Connections still open after {:?}, closing them",
+            config.shutdown_timeout
+        );
+        http.abort();
+        smtp.abort();
+    }
+
+    if timeout(config.shutdown_timeout, pool.close())
+        .await
+        .is_err()
+    {
+        warn!("Database connections still in use, leaving them behind");
     }
+    info!("Shut down");
 
     Ok(())
 }
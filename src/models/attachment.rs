@@ -0,0 +1,236 @@
+//! # This model works on top of the `attachments` SQL table
+//!
+//! ```sql
+//!     CREATE TABLE "attachments" (
+//!       "id" SERIAL PRIMARY KEY,
+//!       "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+//!       "reference" TEXT NOT NULL,
+//!       "entry_id" INTEGER NOT NULL,
+//!       "filename" TEXT NOT NULL,
+//!       "content_type" TEXT NOT NULL,
+//!       "size" INTEGER NOT NULL,
+//!       "data" BYTEA NOT NULL,
+//!       "content_id" TEXT
+//!     );
+//! ```
+//!
+//! Images embedded in an HTML body have a `content_id` and are shown inline,
+//! every other attachment is listed as an enclosure in the feed.
+
+use crate::database::Pool;
+
+/// A file that came along with an [`Entry`](crate::models::Entry), without
+/// its contents, which are only loaded when it's downloaded.
+#[derive(Debug, sqlx::FromRow)]
+pub struct Attachment {
+    pub id: i32,
+    pub entry_id: i32,
+    pub reference: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i32,
+    pub content_id: Option<String>,
+}
+
+/// An attachment as found in an email, before it's stored.
+#[derive(Debug, PartialEq)]
+pub struct NewAttachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+    pub content_id: Option<String>,
+}
+
+/// Whether `content_type` is matched by `pattern`, which can be either a
+/// full type (`application/pdf`) or a whole family of them (`audio/*`).
+fn type_matches(pattern: &str, content_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some("*") => true,
+        Some(family) => content_type
+            .split_once('/')
+            .map(|(kind, _)| kind.eq_ignore_ascii_case(family))
+            .unwrap_or(false),
+        None => pattern.eq_ignore_ascii_case(content_type),
+    }
+}
+
+impl NewAttachment {
+    /// Keeps only the characters of `filename` that are safe in both a URL
+    /// path and a `Content-Disposition` header, along with its extension.
+    pub fn new(filename: &str, content_type: &str, data: Vec<u8>) -> Self {
+        // Leave out any folders a sender may have slipped into the name
+        let basename = filename.rsplit(['/', '\\']).next().unwrap_or("");
+        let filename: String = basename
+            .trim()
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || "._-".contains(c) {
+                true => c,
+                false => '_',
+            })
+            .collect();
+        let filename = match filename.trim_start_matches('.') {
+            "" => "attachment".to_owned(),
+            filename => filename.to_owned(),
+        };
+
+        NewAttachment {
+            filename,
+            content_type: content_type.to_ascii_lowercase(),
+            data,
+            content_id: None,
+        }
+    }
+
+    /// Checks the attachment is no larger than `max_size` bytes and has one
+    /// of the allowed content `types`, explaining why not otherwise.
+    pub fn check(
+        &self,
+        max_size: usize,
+        types: &[String],
+    ) -> Result<(), String> {
+        if self.data.len() > max_size {
+            return Err(format!(
+                "{} is {} bytes, over the {} bytes limit",
+                self.filename,
+                self.data.len(),
+                max_size
+            ));
+        }
+
+        match types
+            .iter()
+            .any(|pattern| type_matches(pattern, &self.content_type))
+        {
+            true => Ok(()),
+            false => Err(format!(
+                "{} is {}, which isn't allowed",
+                self.filename, self.content_type
+            )),
+        }
+    }
+
+    /// Stores the attachment for the [`Entry`](crate::models::Entry) with
+    /// `entry_id` in the [`Feed`](crate::models::Feed) with `reference`.
+    pub async fn save(
+        &self,
+        reference: &str,
+        entry_id: i32,
+        pool: &Pool,
+    ) -> Result<Attachment, sqlx::Error> {
+        let id: i32 = sqlx::query_scalar(
+            r#"INSERT INTO "attachments"
+                ("reference", "entry_id", "filename", "content_type", "size",
+                "data", "content_id")
+                VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING "id""#,
+        )
+        .bind(reference)
+        .bind(entry_id)
+        .bind(&self.filename)
+        .bind(&self.content_type)
+        .bind(self.data.len() as i32)
+        .bind(&self.data)
+        .bind(&self.content_id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(Attachment {
+            id,
+            entry_id,
+            reference: reference.to_owned(),
+            filename: self.filename.clone(),
+            content_type: self.content_type.clone(),
+            size: self.data.len() as i32,
+            content_id: self.content_id.clone(),
+        })
+    }
+}
+
+impl Attachment {
+    /// Returns every [`Attachment`] in a given [`Feed`](crate::models::Feed)
+    pub async fn find_by_reference(
+        reference: &str,
+        pool: &Pool,
+    ) -> Result<Vec<Attachment>, sqlx::Error> {
+        sqlx::query_as::<_, Attachment>(
+            r#"SELECT id, entry_id, reference, filename, content_type, size,
+            content_id FROM attachments WHERE reference = $1 ORDER BY id"#,
+        )
+        .bind(reference)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Returns a single [`Attachment`] given its `id` and the reference of
+    /// the [`Feed`](crate::models::Feed) it belongs to.
+    pub async fn find_by_id(
+        reference: &str,
+        id: i32,
+        pool: &Pool,
+    ) -> Result<Attachment, sqlx::Error> {
+        sqlx::query_as::<_, Attachment>(
+            r#"SELECT id, entry_id, reference, filename, content_type, size,
+            content_id FROM attachments WHERE reference = $1 AND id = $2"#,
+        )
+        .bind(reference)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Where the file is served from.
+    pub fn url(&self, web_url: &str) -> String {
+        format!(
+            "{}/attachments/{}/{}/{}",
+            web_url, self.reference, self.id, self.filename
+        )
+    }
+
+    /// Loads the contents of the file.
+    pub async fn data(&self, pool: &Pool) -> Result<Vec<u8>, sqlx::Error> {
+        sqlx::query_scalar("SELECT data FROM attachments WHERE id = $1")
+            .bind(self.id)
+            .fetch_one(pool)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{type_matches, NewAttachment};
+
+    #[test]
+    fn filenames_are_made_safe() {
+        let name =
+            |filename| NewAttachment::new(filename, "x/y", vec![]).filename;
+
+        assert_eq!(name("report.pdf"), "report.pdf");
+        assert_eq!(name("../../etc/passwd"), "passwd");
+        assert_eq!(name(r"C:\Users\me\Épisode 1.mp3"), "_pisode_1.mp3");
+        assert_eq!(name("a\"b\r\nc.txt"), "a_b__c.txt");
+        assert_eq!(name(".."), "attachment");
+        assert_eq!(name(""), "attachment");
+    }
+
+    #[test]
+    fn content_types() {
+        assert!(type_matches("application/pdf", "application/pdf"));
+        assert!(type_matches("Audio/*", "audio/mpeg"));
+        assert!(type_matches("*/*", "text/html"));
+        assert!(!type_matches("audio/*", "application/audio"));
+        assert!(!type_matches("application/pdf", "application/pdfx"));
+    }
+
+    #[test]
+    fn size_and_type_limits() {
+        let types = ["image/*".to_owned(), "application/pdf".to_owned()];
+        let pdf = NewAttachment::new("a.pdf", "Application/PDF", vec![0; 10]);
+
+        assert_eq!(pdf.content_type, "application/pdf");
+        assert!(pdf.check(10, &types).is_ok());
+        assert!(pdf.check(9, &types).is_err());
+        assert!(pdf.check(10, &[]).is_err());
+        assert!(NewAttachment::new("a.html", "text/html", vec![])
+            .check(10, &types)
+            .is_err());
+    }
+}
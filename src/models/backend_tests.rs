@@ -0,0 +1,319 @@
+//! # Backend test suite
+//!
+//! Every query the models make, run against each of the backends: an
+//! in-memory SQLite database always, and the Postgres one at
+//! `TEST_DATABASE_URL` when that's set, as it is in CI. Feeds get random
+//! references, so the Postgres database can be reused between runs.
+
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::database::{connect, migrate, pending_migrations, Backend, Pool};
+use crate::models::{
+    Attachment, Entry, Feed, NewAttachment, NewFeed, Saved, Unsubscribe,
+};
+
+/// Whether the Postgres database was migrated already. Tests run at the
+/// same time, and creating the migrations table from several of them at
+/// once fails.
+static POSTGRES_MIGRATED: Mutex<bool> = Mutex::const_new(false);
+
+/// Migrated pools for every backend available: a fresh SQLite database,
+/// and the shared Postgres one.
+async fn backends() -> Vec<Pool> {
+    let sqlite = connect("sqlite::memory:", 1).await.unwrap();
+    migrate(&sqlite).await.unwrap();
+    let mut pools = vec![sqlite];
+
+    if let Ok(url) = std::env::var("TEST_DATABASE_URL") {
+        let postgres = connect(&url, 2).await.unwrap();
+        let mut migrated = POSTGRES_MIGRATED.lock().await;
+        if !*migrated {
+            migrate(&postgres).await.unwrap();
+            *migrated = true;
+        }
+        pools.push(postgres);
+    }
+
+    pools
+}
+
+async fn new_feed(title: &str, pool: &Pool) -> String {
+    NewFeed {
+        title: title.to_owned(),
+        reference: None,
+    }
+    .save(pool, &Config::for_tests())
+    .await
+    .unwrap()
+}
+
+fn entry(reference: &str, title: &str) -> Entry {
+    Entry {
+        author: "news@example.com".to_owned(),
+        author_email: Some("news@example.com".to_owned()),
+        message_id: Some(format!("<{}@example.com>", title)),
+        digest: Some(format!("digest of {}", title)),
+        sender_domain: Some("example.com".to_owned()),
+        ..Entry::for_tests(reference, title)
+    }
+}
+
+#[tokio::test]
+async fn migrations_are_applied_once() {
+    let pool = connect("sqlite::memory:", 1).await.unwrap();
+    let migrator = Backend::of(&pool).migrator();
+
+    assert_eq!(
+        pending_migrations(&pool).await.unwrap().len(),
+        migrator.iter().count()
+    );
+    assert_eq!(migrate(&pool).await.unwrap().len(), migrator.iter().count());
+    assert!(pending_migrations(&pool).await.unwrap().is_empty());
+    assert!(migrate(&pool).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn feeds() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+
+        assert!(Feed::feed_exists(&reference, &pool).await.unwrap());
+        assert!(!Feed::feed_exists("nope", &pool).await.unwrap());
+        assert_eq!(
+            Feed::get_title_given_reference(&reference, &pool)
+                .await
+                .unwrap(),
+            "Weekly"
+        );
+
+        let entries = Entry::find_by_reference(&reference, None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].title, "Weekly inbox created!");
+        assert!(!entries[0].unsubscribe_one_click);
+    }
+}
+
+#[tokio::test]
+async fn placeholder_titles_are_renamed_once() {
+    for pool in backends().await {
+        let placeholders = ["Untitled".to_owned()];
+        let reference = new_feed(" untitled ", &pool).await;
+
+        assert!(Feed::rename_placeholder(
+            &reference,
+            "Weekly",
+            &placeholders,
+            &pool
+        )
+        .await
+        .unwrap());
+        assert!(!Feed::rename_placeholder(
+            &reference,
+            "Daily",
+            &placeholders,
+            &pool
+        )
+        .await
+        .unwrap());
+        assert_eq!(
+            Feed::get_title_given_reference(&reference, &pool)
+                .await
+                .unwrap(),
+            "Weekly"
+        );
+    }
+}
+
+#[tokio::test]
+async fn entries_need_a_feed() {
+    for pool in backends().await {
+        assert!(entry("nope", "Issue 1").save(&pool).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn entries_are_deduplicated() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+        let first = entry(&reference, "Issue 1");
+
+        let id = match first.save(&pool).await.unwrap() {
+            Saved::Inserted(id) => id,
+            saved => panic!("{:?}", saved),
+        };
+        assert_eq!(first.save(&pool).await.unwrap(), Saved::Duplicate(id));
+
+        let same_content = Entry {
+            message_id: Some("<resent@example.com>".to_owned()),
+            ..entry(&reference, "Issue 1")
+        };
+        assert_eq!(
+            same_content.save(&pool).await.unwrap(),
+            Saved::Duplicate(id)
+        );
+
+        let other_tag = Entry {
+            tag: Some("rust".to_owned()),
+            ..entry(&reference, "Issue 1")
+        };
+        let tagged = match other_tag.save(&pool).await.unwrap() {
+            Saved::Inserted(tagged) => tagged,
+            saved => panic!("{:?}", saved),
+        };
+        assert_ne!(tagged, id);
+        assert_eq!(
+            other_tag.save(&pool).await.unwrap(),
+            Saved::Duplicate(tagged)
+        );
+
+        let second = entry(&reference, "Issue 2").save(&pool).await.unwrap();
+        assert!(matches!(second, Saved::Inserted(other) if other != id));
+
+        let saved = Entry::find_by_id(&reference, id, &pool).await.unwrap();
+        assert_eq!(saved.title, "Issue 1");
+        assert_eq!(saved.author_email.as_deref(), Some("news@example.com"));
+        assert_eq!(saved.sender_domain.as_deref(), Some("example.com"));
+        assert_eq!(saved.tag, None);
+        assert!(Entry::find_by_id("nope", id, &pool).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn entries_are_filtered_by_tag() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+        let tagged = Entry {
+            tag: Some("rust".to_owned()),
+            ..entry(&reference, "Issue 1")
+        };
+        tagged.save(&pool).await.unwrap();
+        entry(&reference, "Issue 2").save(&pool).await.unwrap();
+
+        let all = Entry::find_by_reference(&reference, None, &pool)
+            .await
+            .unwrap();
+        let rust = Entry::find_by_reference(&reference, Some("rust"), &pool)
+            .await
+            .unwrap();
+        let go = Entry::find_by_reference(&reference, Some("go"), &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(all.len(), 3);
+        assert_eq!(rust.len(), 1);
+        assert_eq!(rust[0].title, "Issue 1");
+        assert!(go.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn entry_content_is_replaced() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+        let id = match entry(&reference, "Issue 1").save(&pool).await.unwrap() {
+            Saved::Inserted(id) => id,
+            saved => panic!("{:?}", saved),
+        };
+
+        Entry::set_content(&reference, id, "<p>Rewritten</p>", &pool)
+            .await
+            .unwrap();
+
+        let saved = Entry::find_by_id(&reference, id, &pool).await.unwrap();
+        assert_eq!(saved.content, "<p>Rewritten</p>");
+    }
+}
+
+#[tokio::test]
+async fn confirmations_are_found() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+        for (n, created_at) in ["2022-10-20", "2022-10-22", "2022-10-21"]
+            .iter()
+            .enumerate()
+        {
+            Entry {
+                created_at: created_at.to_string(),
+                confirmation: true,
+                confirmation_url: Some(format!("https://example.com/{}", n)),
+                ..entry(&reference, &format!("Confirm {}", n))
+            }
+            .save(&pool)
+            .await
+            .unwrap();
+        }
+        entry(&reference, "Issue 1").save(&pool).await.unwrap();
+
+        let found = Entry::find_confirmations(&reference, 2, &pool)
+            .await
+            .unwrap();
+        let titles: Vec<_> = found.iter().map(|e| e.title.as_str()).collect();
+
+        assert_eq!(titles, ["Confirm 1", "Confirm 2"]);
+        assert!(found.iter().all(|e| e.confirmation));
+    }
+}
+
+#[tokio::test]
+async fn attachments() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+        let entry_id =
+            match entry(&reference, "Issue 1").save(&pool).await.unwrap() {
+                Saved::Inserted(id) => id,
+                saved => panic!("{:?}", saved),
+            };
+        let data = vec![0, 159, 146, 150, 255];
+        let saved =
+            NewAttachment::new("a.pdf", "application/pdf", data.clone())
+                .save(&reference, entry_id, &pool)
+                .await
+                .unwrap();
+
+        let found = Attachment::find_by_reference(&reference, &pool)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, saved.id);
+        assert_eq!(found[0].entry_id, entry_id);
+        assert_eq!(found[0].size, 5);
+        assert_eq!(found[0].content_id, None);
+
+        let by_id = Attachment::find_by_id(&reference, saved.id, &pool)
+            .await
+            .unwrap();
+        assert_eq!(by_id.filename, "a.pdf");
+        assert_eq!(by_id.data(&pool).await.unwrap(), data);
+        assert!(Attachment::find_by_id("nope", saved.id, &pool)
+            .await
+            .is_err());
+    }
+}
+
+#[tokio::test]
+async fn unsubscribe() {
+    for pool in backends().await {
+        let reference = new_feed("Weekly", &pool).await;
+
+        assert_eq!(
+            Unsubscribe::of_feed(&reference, &pool).await.unwrap(),
+            Some(Unsubscribe::default())
+        );
+        assert_eq!(Unsubscribe::of_feed("nope", &pool).await.unwrap(), None);
+
+        let unsubscribe = Unsubscribe {
+            url: Some("https://example.com/unsubscribe".to_owned()),
+            mailto: Some("mailto:leave@example.com".to_owned()),
+            one_click: true,
+        };
+        unsubscribe.save_for_feed(&reference, &pool).await.unwrap();
+
+        assert_eq!(
+            Unsubscribe::of_feed(&reference, &pool).await.unwrap(),
+            Some(unsubscribe)
+        );
+    }
+}
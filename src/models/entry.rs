@@ -5,19 +5,45 @@
  *    CREATE TABLE "entries" (
  *        "id" INTEGER PRIMARY KEY AUTOINCREMENT,
  *        "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
- *        "reference" TEXT NOT NULL UNIQUE,
+ *        "reference" TEXT NOT NULL,
  *        "title" TEXT NOT NULL,
  *        "author" TEXT NOT NULL,
- *        "content" TEXT NOT NULL
+ *        "content" TEXT NOT NULL,
+ *        "author_name" TEXT,
+ *        "author_email" TEXT,
+ *        "unsubscribe_url" TEXT,
+ *        "unsubscribe_mailto" TEXT,
+ *        "unsubscribe_one_click" BOOLEAN NOT NULL DEFAULT FALSE,
+ *        "message_id" TEXT,
+ *        "digest" TEXT,
+ *        "tag" TEXT,
+ *        "list_id" TEXT,
+ *        "list_name" TEXT,
+ *        "list_post" TEXT,
+ *        "sender_domain" TEXT,
+ *        "confirmation" BOOLEAN NOT NULL DEFAULT FALSE,
+ *        "confirmation_url" TEXT,
+ *        FOREIGN KEY(reference) REFERENCES feeds(reference)
  *    );
+ *    CREATE INDEX "entriesRef" ON "entries" ("reference");
+ *    CREATE INDEX "entriesTag" ON "entries" ("reference", "tag");
+ *    CREATE UNIQUE INDEX "entriesTagMessageId"
+ *        ON "entries" ("reference", (COALESCE("tag", '')), "message_id");
+ *    CREATE UNIQUE INDEX "entriesTagDigest"
+ *        ON "entries" ("reference", (COALESCE("tag", '')), "digest");
  * ```
+ *
+ * Senders retry when they don't hear back in time, so the same email can
+ * be delivered more than once. Its Message-ID, or failing that the digest
+ * of its content, tells whether a [`Feed`] already has it under the same
+ * tag.
 */
 
 use std::error::Error;
 use tracing::debug;
 
 use crate::database::{DatabaseError, Pool};
-use crate::models::Feed;
+use crate::models::{Feed, Unsubscribe};
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct Entry {
@@ -25,8 +51,69 @@ pub struct Entry {
     pub created_at: String,
     pub reference: String,
     pub title: String,
+    /// What's shown as the author, its name or else its address
     pub author: String,
     pub content: String,
+    /// The sender as parsed from the From header, when there is one
+    pub author_name: Option<String>,
+    pub author_email: Option<String>,
+    /// How to unsubscribe, as per the email's List-Unsubscribe headers
+    pub unsubscribe_url: Option<String>,
+    pub unsubscribe_mailto: Option<String>,
+    pub unsubscribe_one_click: bool,
+    /// The email's Message-ID, without its angle brackets
+    pub message_id: Option<String>,
+    /// A SHA-256 of the sender, subject, date and content, in hex
+    pub digest: Option<String>,
+    /// What came after the `+` in the address it was sent to, if anything
+    pub tag: Option<String>,
+    /// The mailing list it came through, as per its List-Id and List-Post
+    pub list_id: Option<String>,
+    pub list_name: Option<String>,
+    pub list_post: Option<String>,
+    /// The domain of the sender's address, lowercased
+    pub sender_domain: Option<String>,
+    /// Whether it asks to confirm a subscription, and the link to do so
+    pub confirmation: bool,
+    pub confirmation_url: Option<String>,
+}
+
+/// What became of an [`Entry`] being saved, along with its `id` either way.
+#[derive(Debug, PartialEq)]
+pub enum Saved {
+    Inserted(i32),
+    /// The [`Feed`] had it already, so it was left alone
+    Duplicate(i32),
+}
+
+#[cfg(test)]
+impl Entry {
+    /// An [`Entry`] with nothing but a `reference` and `title` for tests
+    /// elsewhere in the crate, the rest filled in with struct update syntax.
+    pub fn for_tests(reference: &str, title: &str) -> Entry {
+        Entry {
+            id: 1,
+            created_at: "2022-10-05 06:00:00".to_owned(),
+            reference: reference.to_owned(),
+            title: title.to_owned(),
+            author: "News".to_owned(),
+            content: "<p>Hi</p>".to_owned(),
+            author_name: None,
+            author_email: None,
+            unsubscribe_url: None,
+            unsubscribe_mailto: None,
+            unsubscribe_one_click: false,
+            message_id: None,
+            digest: None,
+            tag: None,
+            list_id: None,
+            list_name: None,
+            list_post: None,
+            sender_domain: None,
+            confirmation: false,
+            confirmation_url: None,
+        }
+    }
 }
 
 impl std::fmt::Display for Entry {
@@ -40,22 +127,82 @@ impl std::fmt::Display for Entry {
 }
 
 impl Entry {
-    /// Returns all [`Entry`] records for a given [`Feed`] reference
+    /// How to unsubscribe from the newsletter the [`Entry`] came from.
+    pub fn unsubscribe(&self) -> Unsubscribe {
+        Unsubscribe {
+            url: self.unsubscribe_url.clone(),
+            mailto: self.unsubscribe_mailto.clone(),
+            one_click: self.unsubscribe_one_click,
+        }
+    }
+
+    /// Returns all [`Entry`] records for a given [`Feed`] reference, only
+    /// those with `tag` if there's one.
     pub async fn find_by_reference(
         reference: &str,
+        tag: Option<&str>,
+        pool: &Pool,
+    ) -> Result<Vec<Entry>, sqlx::Error> {
+        sqlx::query_as::<_, Entry>(
+            r#"SELECT id, created_at, reference, title, author, content,
+            author_name, author_email, unsubscribe_url, unsubscribe_mailto,
+            unsubscribe_one_click, message_id, digest, tag, list_id, list_name,
+            list_post, sender_domain, confirmation, confirmation_url FROM entries
+            WHERE reference = $1 AND (CAST($2 AS TEXT) IS NULL OR tag = $2)
+            ORDER BY created_at DESC"#,
+        )
+        .bind(reference)
+        .bind(tag)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Returns the latest subscription confirmations in a given [`Feed`],
+    /// `limit` of them at most.
+    pub async fn find_confirmations(
+        reference: &str,
+        limit: i64,
         pool: &Pool,
     ) -> Result<Vec<Entry>, sqlx::Error> {
         sqlx::query_as::<_, Entry>(
-            r#"SELECT id, created_at, reference, title, author, content
-            FROM entries WHERE reference = $1 ORDER BY created_at DESC"#,
+            r#"SELECT id, created_at, reference, title, author, content,
+            author_name, author_email, unsubscribe_url, unsubscribe_mailto,
+            unsubscribe_one_click, message_id, digest, tag, list_id, list_name,
+            list_post, sender_domain, confirmation, confirmation_url FROM entries
+            WHERE reference = $1 AND confirmation
+            ORDER BY created_at DESC LIMIT $2"#,
         )
         .bind(reference)
+        .bind(limit)
         .fetch_all(pool)
         .await
     }
 
-    /// Saves the [`Entry`] to the database, unless the [`Feed`] doesn't exist.
-    pub async fn save(&self, pool: &Pool) -> Result<(), Box<dyn Error>> {
+    /// Returns a single [`Entry`] given its `id` and the reference of the
+    /// [`Feed`] it belongs to, so entries can't be enumerated across feeds.
+    pub async fn find_by_id(
+        reference: &str,
+        id: i32,
+        pool: &Pool,
+    ) -> Result<Entry, sqlx::Error> {
+        sqlx::query_as::<_, Entry>(
+            r#"SELECT id, created_at, reference, title, author, content,
+            author_name, author_email, unsubscribe_url, unsubscribe_mailto,
+            unsubscribe_one_click, message_id, digest, tag, list_id, list_name,
+            list_post, sender_domain, confirmation, confirmation_url FROM entries
+            WHERE reference = $1 AND id = $2"#,
+        )
+        .bind(reference)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Saves the [`Entry`] to the database, unless the [`Feed`] doesn't exist,
+    /// returning the `id` it was given. Should the [`Feed`] have it already
+    /// under the same `tag`, going by its `message_id` or `digest`, that
+    /// one's `id` is returned.
+    pub async fn save(&self, pool: &Pool) -> Result<Saved, Box<dyn Error>> {
         if !Feed::feed_exists(&self.reference, pool).await? {
             let err: Box<dyn Error> = format!(
                 "Tried saving Entry for Feed ref:{} which didn't exist",
@@ -65,25 +212,64 @@ impl Entry {
             return Err(err);
         }
 
-        let (n_rows,): (i64,) = sqlx::query_as(
-            r#"WITH inserted AS (INSERT INTO "entries"
-                ("reference", "title", "author", "content", "created_at")
-                VALUES ($1, $2, $3, $4, $5) RETURNING 1)
-                SELECT COUNT(*) FROM inserted;"#,
+        let inserted: Option<i32> = sqlx::query_scalar(
+            r#"INSERT INTO "entries"
+                ("reference", "title", "author", "content", "created_at",
+                "author_name", "author_email", "unsubscribe_url",
+                "unsubscribe_mailto", "unsubscribe_one_click", "message_id",
+                "digest", "tag", "list_id", "list_name", "list_post",
+                "sender_domain", "confirmation", "confirmation_url")
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                $13, $14, $15, $16, $17, $18, $19)
+                ON CONFLICT DO NOTHING RETURNING "id";"#,
         )
         .bind(&self.reference)
         .bind(&self.title)
-        // We don't need the address for display within the feed
-        .bind(&self.author.split('<').next().unwrap_or("").trim())
+        .bind(&self.author)
         .bind(&self.content)
         .bind(&self.created_at)
-        .fetch_one(pool)
+        .bind(&self.author_name)
+        .bind(&self.author_email)
+        .bind(&self.unsubscribe_url)
+        .bind(&self.unsubscribe_mailto)
+        .bind(self.unsubscribe_one_click)
+        .bind(&self.message_id)
+        .bind(&self.digest)
+        .bind(&self.tag)
+        .bind(&self.list_id)
+        .bind(&self.list_name)
+        .bind(&self.list_post)
+        .bind(&self.sender_domain)
+        .bind(self.confirmation)
+        .bind(&self.confirmation_url)
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some(id) = inserted {
+            return Ok(Saved::Inserted(id));
+        }
+
+        let existing: Option<i32> = sqlx::query_scalar(
+            r#"SELECT "id" FROM "entries" WHERE "reference" = $1
+                AND COALESCE("tag", '') = COALESCE(CAST($4 AS TEXT), '')
+                AND ("message_id" = $2 OR "digest" = $3)"#,
+        )
+        .bind(&self.reference)
+        .bind(&self.message_id)
+        .bind(&self.digest)
+        .bind(&self.tag)
+        .fetch_optional(pool)
         .await?;
 
-        match n_rows {
-            n_rows if n_rows > 0 => Ok(()),
-            n_rows if n_rows == 0 => Ok(()),
-            _ => {
+        match existing {
+            Some(id) => {
+                debug!(
+                    "Entry:{} for ref:{} was already there as {}",
+                    &self, &self.reference, id
+                );
+                Ok(Saved::Duplicate(id))
+            }
+            None => {
                 debug!(
                     "Couldn't INSERT entry:{} for ref:{}",
                     &self, &self.reference
@@ -92,4 +278,24 @@ impl Entry {
             }
         }
     }
+
+    /// Replaces the `content` of an already saved [`Entry`].
+    pub async fn set_content(
+        reference: &str,
+        id: i32,
+        content: &str,
+        pool: &Pool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"UPDATE "entries" SET "content" = $3
+            WHERE "reference" = $1 AND "id" = $2"#,
+        )
+        .bind(reference)
+        .bind(id)
+        .bind(content)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
 }
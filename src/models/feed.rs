@@ -3,10 +3,13 @@
 //! ```sql
 //!     CREATE TABLE "feeds" (
 //!       "id" INTEGER PRIMARY KEY AUTOINCREMENT,
-//!       "createdAt" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
-//!       "updatedAt" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+//!       "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+//!       "updated_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
 //!       "reference" TEXT NOT NULL UNIQUE,
-//!       "title" TEXT NOT NULL
+//!       "title" TEXT NOT NULL,
+//!       "unsubscribe_url" TEXT,
+//!       "unsubscribe_mailto" TEXT,
+//!       "unsubscribe_one_click" BOOLEAN NOT NULL DEFAULT FALSE
 //!     );
 //! ```
 
@@ -16,8 +19,9 @@ use serde::{Deserialize, Serialize};
 use std::error::Error;
 use tracing::debug;
 
+use crate::config::Config;
 use crate::database::{DatabaseError, Pool};
-use crate::vars::{EMAIL_DOMAIN, WEB_URL};
+use crate::models::{Entry, UnsubscribeTemplate};
 
 /// A helper Struct to pass on to Axum so it can deserialize a form submission
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -54,6 +58,39 @@ impl Feed {
         Ok(title)
     }
 
+    /// Renames the [`Feed`] with `reference` to `title`, as long as it's
+    /// still called one of the `placeholders`, ignoring case. Returns
+    /// whether it was renamed.
+    pub async fn rename_placeholder(
+        reference: &str,
+        title: &str,
+        placeholders: &[String],
+        pool: &Pool,
+    ) -> Result<bool, sqlx::Error> {
+        let current = Feed::get_title_given_reference(reference, pool).await?;
+        let current = current.trim().to_lowercase();
+        if !placeholders
+            .iter()
+            .any(|placeholder| placeholder.trim().to_lowercase() == current)
+        {
+            return Ok(false);
+        }
+
+        // Unless it was renamed in the meantime
+        let renamed = sqlx::query(
+            r#"UPDATE "feeds" SET "title" = $2,
+            "updated_at" = CURRENT_TIMESTAMP
+            WHERE "reference" = $1 AND LOWER(TRIM("title")) = $3"#,
+        )
+        .bind(reference)
+        .bind(title)
+        .bind(current)
+        .execute(pool)
+        .await?;
+
+        Ok(renamed.rows_affected() > 0)
+    }
+
     /// Checks whether a [`Feed`] exists given its `reference`.
     pub async fn feed_exists(
         reference: &str,
@@ -82,14 +119,23 @@ pub struct SentinelTemplate<'a> {
     pub web_url: &'a str,
 }
 
+/// Subscription confirmations pinned to the top of a feed's page.
+#[derive(Template, Copy, Clone)]
+#[template(path = "confirmations.html", ext = "html")]
+pub struct ConfirmationsTemplate<'a> {
+    pub web_url: &'a str,
+    pub entries: &'a [Entry],
+}
+
 #[derive(Template, Copy, Clone)]
 #[template(path = "created.html", ext = "html", escape = "none")]
 pub struct FeedCreatedTemplate<'a> {
-    pub email_domain: &'a str,
-    pub reference: &'a str,
-    pub title: &'a str,
     pub web_url: &'a str,
     pub entry: SentinelTemplate<'a>,
+    /// Newsletters waiting for their subscription to be confirmed
+    pub confirmations: Option<ConfirmationsTemplate<'a>>,
+    /// How to unsubscribe, once a newsletter told us
+    pub unsubscribe: Option<UnsubscribeTemplate<'a>>,
 }
 
 impl NewFeed {
@@ -102,29 +148,23 @@ impl NewFeed {
     pub async fn save(
         &mut self,
         pool: &Pool,
+        config: &Config,
     ) -> Result<String, Box<dyn Error>> {
         let reference = self
             .reference
             .get_or_insert_with(NewFeed::new_reference)
             .to_owned();
 
-        let _inserted: i64 = match sqlx::query_as(
-            r#"WITH inserted AS (
-                INSERT INTO "feeds" ("reference", "title") VALUES ($1, $2)
-            RETURNING 1) SELECT COUNT(*) FROM inserted;"#,
+        let inserted = sqlx::query(
+            r#"INSERT INTO "feeds" ("reference", "title") VALUES ($1, $2)"#,
         )
         .bind(self.reference.as_ref().unwrap())
         .bind(&self.title)
-        .fetch_one(pool)
-        .await
-        {
-            Ok(tup) => {
-                if matches!(tup, (_inserted,)) && tup.0 > 0 {
-                    tup.0
-                } else {
-                    return Err(Box::new(DatabaseError::CouldNotInsert));
-                }
-            }
+        .execute(pool)
+        .await;
+        match inserted {
+            Ok(result) if result.rows_affected() > 0 => {}
+            Ok(_) => return Err(Box::new(DatabaseError::CouldNotInsert)),
             Err(e) => {
                 debug!(
                     "Couldn't INSERT feed ref:{:?} title:{} ({})",
@@ -135,28 +175,27 @@ impl NewFeed {
         };
 
         let content = SentinelTemplate {
-            email_domain: EMAIL_DOMAIN,
+            email_domain: &config.email_domain,
             reference: self.reference.as_ref().unwrap(),
             title: &self.title,
-            web_url: WEB_URL,
+            web_url: &config.web_url,
         };
         let content = content.render().unwrap();
 
         let entry_title = format!("{} inbox created!", self.title);
 
-        let (n_rows,): (i64,) = sqlx::query_as(concat!(
-            r#"WITH inserted AS (
-                INSERT INTO "entries"
-                ("reference", "title", "author", "content")
-                VALUES ($1, $2, $3, $4) RETURNING 1)
-                SELECT count(*) FROM inserted;"#
-        ))
+        let n_rows = sqlx::query(
+            r#"INSERT INTO "entries"
+            ("reference", "title", "author", "content")
+            VALUES ($1, $2, $3, $4)"#,
+        )
         .bind(self.reference.as_ref().unwrap())
         .bind(entry_title)
         .bind("Kill The Newsletter")
         .bind(content)
-        .fetch_one(pool)
-        .await?;
+        .execute(pool)
+        .await?
+        .rows_affected();
 
         match n_rows {
             n_rows if n_rows > 0 => Ok(reference),
@@ -170,20 +209,64 @@ impl NewFeed {
         }
     }
 
-    pub fn created_template(&self) -> FeedCreatedTemplate {
+    pub fn created_template<'a>(
+        &'a self,
+        config: &'a Config,
+    ) -> FeedCreatedTemplate<'a> {
         let entry = SentinelTemplate {
-            email_domain: EMAIL_DOMAIN,
+            email_domain: &config.email_domain,
             reference: self.reference.as_ref().unwrap(),
             title: &self.title,
-            web_url: WEB_URL,
+            web_url: &config.web_url,
         };
 
         FeedCreatedTemplate {
-            email_domain: EMAIL_DOMAIN,
-            reference: self.reference.as_ref().unwrap(),
-            title: &self.title,
-            web_url: WEB_URL,
+            web_url: &config.web_url,
             entry,
+            confirmations: None,
+            unsubscribe: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ConfirmationsTemplate;
+    use crate::models::Entry;
+    use askama::Template;
+
+    #[test]
+    fn confirmations_are_pinned() {
+        let entry = |id, url: Option<&str>| Entry {
+            id,
+            confirmation: true,
+            confirmation_url: url.map(str::to_owned),
+            ..Entry::for_tests("abc", "Please confirm")
+        };
+        let entries = [
+            entry(2, Some("https://letter.example/confirm?a=1&b=2")),
+            entry(1, None),
+        ];
+
+        let html = ConfirmationsTemplate {
+            web_url: "https://ktnrs.com",
+            entries: &entries,
+        }
+        .render()
+        .unwrap();
+
+        assert!(
+            html.contains(r#"href="https://ktnrs.com/alternates/abc/2.html""#)
+        );
+        assert!(
+            html.contains(r#"href="https://ktnrs.com/alternates/abc/1.html""#)
+        );
+        assert_eq!(
+            html.matches(
+                r#"href="https://letter.example/confirm?a=1&amp;b=2""#
+            )
+            .count(),
+            1
+        );
+    }
+}
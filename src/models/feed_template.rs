@@ -2,7 +2,7 @@
 
 use askama_axum::Template;
 
-use crate::models::Entry;
+use crate::models::{Attachment, Entry};
 use crate::time::filters;
 
 #[derive(Template)]
@@ -12,5 +12,184 @@ pub struct FeedAtomTemplate {
     pub email_domain: String,
     pub feed_title: String,
     pub feed_reference: String,
+    /// The tag the entries were filtered by, if any
+    pub feed_tag: Option<String>,
+    /// When the latest entry came in
+    pub updated: String,
     pub entries: Vec<Entry>,
+    /// Every entry's attachments, listed as its enclosures unless they're
+    /// inline images
+    pub attachments: Vec<Attachment>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeedAtomTemplate;
+    use crate::models::{Attachment, Entry};
+    use askama_axum::Template;
+
+    /// A feed of `entries` without attachments, the rest filled in with
+    /// struct update syntax.
+    fn template(entries: Vec<Entry>) -> FeedAtomTemplate {
+        FeedAtomTemplate {
+            web_url: "https://ktnrs.com".to_owned(),
+            email_domain: "ktnrs.com".to_owned(),
+            feed_title: "News".to_owned(),
+            feed_reference: "abc".to_owned(),
+            feed_tag: None,
+            updated: "2022-10-05 06:00:00".to_owned(),
+            entries,
+            attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn attachments_are_enclosures() {
+        let entry = |id| Entry {
+            id,
+            title: "Episode 42".to_owned(),
+            author: "The Podcast".to_owned(),
+            author_name: Some("The Podcast".to_owned()),
+            ..Entry::for_tests("abc", "Hi")
+        };
+        let template = FeedAtomTemplate {
+            feed_title: "Podcast".to_owned(),
+            attachments: vec![
+                Attachment {
+                    id: 7,
+                    entry_id: 2,
+                    reference: "abc".to_owned(),
+                    filename: "episode-42.mp3".to_owned(),
+                    content_type: "audio/mpeg".to_owned(),
+                    size: 12,
+                    content_id: None,
+                },
+                Attachment {
+                    id: 8,
+                    entry_id: 2,
+                    reference: "abc".to_owned(),
+                    filename: "logo.png".to_owned(),
+                    content_type: "image/png".to_owned(),
+                    size: 70,
+                    content_id: Some("logo@news".to_owned()),
+                },
+            ],
+            ..template(vec![entry(2), entry(1)])
+        };
+
+        let xml = template.render().unwrap();
+        let enclosure = concat!(
+            r#"rel="enclosure" type="audio/mpeg" length="12" "#,
+            r#"title="episode-42.mp3" "#,
+            r#"href="https://ktnrs.com/attachments/abc/7/episode-42.mp3""#,
+        );
+        assert_eq!(xml.matches(enclosure).count(), 1);
+        // Inline images are part of the content instead
+        assert!(!xml.contains("logo.png"));
+    }
+
+    #[test]
+    fn authors() {
+        let entry = |author: &str, email: Option<&str>| Entry {
+            author: author.to_owned(),
+            author_email: email.map(str::to_owned),
+            ..Entry::for_tests("abc", "Hi")
+        };
+        let template = template(vec![
+            entry("Doe, Jane", Some("jane@x.com")),
+            entry("Kill the Newsletter!", None),
+        ]);
+
+        let xml = template.render().unwrap();
+        assert!(xml.contains("<name>Doe, Jane</name>"));
+        assert_eq!(xml.matches("<email>").count(), 1);
+        assert!(xml.contains("<email>jane@x.com</email>"));
+    }
+
+    #[test]
+    fn unsubscribe_links() {
+        let entry = |id, url: Option<&str>, mailto: Option<&str>| Entry {
+            id,
+            unsubscribe_url: url.map(str::to_owned),
+            unsubscribe_mailto: mailto.map(str::to_owned),
+            ..Entry::for_tests("abc", "Hi")
+        };
+        let template = template(vec![
+            entry(
+                3,
+                Some("https://news.example/unsub?a=1&b=2"),
+                Some("mailto:unsub@news.example"),
+            ),
+            entry(2, None, Some("mailto:unsub@news.example")),
+            entry(1, None, None),
+        ]);
+
+        let xml = template.render().unwrap();
+        assert_eq!(xml.matches(r#"title="Unsubscribe""#).count(), 2);
+        assert!(
+            xml.contains(r#"href="https://news.example/unsub?a=1&amp;b=2""#)
+        );
+        assert_eq!(
+            xml.matches(r#"href="mailto:unsub@news.example""#).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn tagged_feeds() {
+        let template = FeedAtomTemplate {
+            feed_tag: Some("a&b".to_owned()),
+            ..template(vec![])
+        };
+
+        let xml = template.render().unwrap();
+        assert!(xml.contains("<id>urn:kill-the-newsletter:abc:a&amp;b</id>"));
+        assert!(xml.contains("<title>News (a&amp;b)</title>"));
+        assert!(
+            xml.contains(r#"href="https://ktnrs.com/feeds/abc.xml?tag=a%26b""#)
+        );
+        assert!(xml.contains("<updated>2022-10-05T06:00:00+00:00</updated>"));
+    }
+
+    #[test]
+    fn categories() {
+        let entry = |id, list_name: Option<&str>| Entry {
+            id,
+            list_id: list_name.map(|_| "news.list.example".to_owned()),
+            list_name: list_name.map(str::to_owned),
+            list_post: list_name.map(|_| "mailto:news@list.example".to_owned()),
+            sender_domain: Some("letter.example".to_owned()),
+            confirmation: list_name.is_none(),
+            ..Entry::for_tests("abc", "Hi")
+        };
+        let template =
+            template(vec![entry(2, Some("News & Views")), entry(1, None)]);
+
+        let xml = template.render().unwrap();
+        assert_eq!(xml.matches("<category").count(), 5);
+        assert_eq!(
+            xml.matches(concat!(
+                r#"<category scheme="urn:kill-the-newsletter:kind" "#,
+                r#"term="confirmation" label="Subscription confirmation" />"#,
+            ))
+            .count(),
+            1
+        );
+        assert!(xml.contains(concat!(
+            r#"<category scheme="urn:kill-the-newsletter:list-id" "#,
+            r#"term="news.list.example" label="News &amp; Views" />"#,
+        )));
+        assert!(xml.contains(concat!(
+            r#"<category scheme="urn:kill-the-newsletter:list-post" "#,
+            r#"term="mailto:news@list.example" />"#,
+        )));
+        assert_eq!(
+            xml.matches(concat!(
+                r#"<category scheme="urn:kill-the-newsletter:sender-domain" "#,
+                r#"term="letter.example" />"#,
+            ))
+            .count(),
+            2
+        );
+    }
 }
@@ -3,10 +3,16 @@
 //! Contains classes reprenting the models / DAOs to be used by both the
 //! web application and the SMTP server.
 
+mod attachment;
+#[cfg(test)]
+mod backend_tests;
 mod entry;
 mod feed;
 mod feed_template;
+mod unsubscribe;
 
-pub use entry::Entry;
-pub use feed::{Feed, FeedCreatedTemplate, NewFeed};
+pub use attachment::{Attachment, NewAttachment};
+pub use entry::{Entry, Saved};
+pub use feed::{ConfirmationsTemplate, Feed, NewFeed};
 pub use feed_template::FeedAtomTemplate;
+pub use unsubscribe::{Unsubscribe, UnsubscribeTemplate};
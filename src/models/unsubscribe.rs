@@ -0,0 +1,125 @@
+//! # How to unsubscribe from a newsletter
+//!
+//! As told by the `List-Unsubscribe` (RFC 2369) and `List-Unsubscribe-Post`
+//! (RFC 8058) headers of its emails. Every [`Entry`](crate::models::Entry)
+//! keeps its own, and the [`Feed`](crate::models::Feed) keeps those of the
+//! latest email that had any, in the `feeds` columns below.
+//!
+//! ```sql
+//!     ALTER TABLE "feeds" ADD COLUMN "unsubscribe_url" TEXT;
+//!     ALTER TABLE "feeds" ADD COLUMN "unsubscribe_mailto" TEXT;
+//!     ALTER TABLE "feeds" ADD COLUMN "unsubscribe_one_click" BOOLEAN
+//!       NOT NULL DEFAULT FALSE;
+//! ```
+
+use askama::Template;
+
+use crate::database::Pool;
+
+#[derive(Debug, Clone, Default, PartialEq, sqlx::FromRow)]
+pub struct Unsubscribe {
+    /// An http(s) URL to unsubscribe at
+    pub url: Option<String>,
+    pub mailto: Option<String>,
+    /// Whether POSTing to the `url` is enough, as per RFC 8058
+    pub one_click: bool,
+}
+
+/// The Unsubscribe action on a feed's page.
+#[derive(Template, Copy, Clone)]
+#[template(path = "unsubscribe.html", ext = "html")]
+pub struct UnsubscribeTemplate<'a> {
+    pub web_url: &'a str,
+    pub reference: &'a str,
+    pub unsubscribe: &'a Unsubscribe,
+}
+
+impl Unsubscribe {
+    pub fn is_empty(&self) -> bool {
+        self.url.is_none() && self.mailto.is_none()
+    }
+
+    /// Returns how to unsubscribe from the [`Feed`](crate::models::Feed)
+    /// with `reference`, or `None` if there's no such feed.
+    pub async fn of_feed(
+        reference: &str,
+        pool: &Pool,
+    ) -> Result<Option<Unsubscribe>, sqlx::Error> {
+        sqlx::query_as::<_, Unsubscribe>(
+            r#"SELECT unsubscribe_url AS url, unsubscribe_mailto AS mailto,
+            unsubscribe_one_click AS one_click
+            FROM feeds WHERE reference = $1"#,
+        )
+        .bind(reference)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Makes this the way to unsubscribe from the
+    /// [`Feed`](crate::models::Feed) with `reference`.
+    pub async fn save_for_feed(
+        &self,
+        reference: &str,
+        pool: &Pool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"UPDATE "feeds" SET "unsubscribe_url" = $2,
+            "unsubscribe_mailto" = $3, "unsubscribe_one_click" = $4,
+            "updated_at" = CURRENT_TIMESTAMP
+            WHERE "reference" = $1"#,
+        )
+        .bind(reference)
+        .bind(&self.url)
+        .bind(&self.mailto)
+        .bind(self.one_click)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Unsubscribe, UnsubscribeTemplate};
+    use askama::Template;
+
+    fn render(unsubscribe: &Unsubscribe) -> String {
+        UnsubscribeTemplate {
+            web_url: "https://ktnrs.com",
+            reference: "abc",
+            unsubscribe,
+        }
+        .render()
+        .unwrap()
+    }
+
+    #[test]
+    fn one_click_is_a_form() {
+        let html = render(&Unsubscribe {
+            url: Some("https://news.example/unsub?a=1&b=2".to_owned()),
+            mailto: Some("mailto:unsub@news.example".to_owned()),
+            one_click: true,
+        });
+
+        assert!(html.contains(
+            r#"<form method="post" action="https://ktnrs.com/feeds/abc/unsubscribe">"#
+        ));
+        assert!(!html.contains("news.example"));
+    }
+
+    #[test]
+    fn otherwise_links() {
+        let html = render(&Unsubscribe {
+            url: Some("https://news.example/unsub?a=1&b=2".to_owned()),
+            mailto: Some("mailto:unsub@news.example".to_owned()),
+            one_click: false,
+        });
+
+        assert!(!html.contains("<form"));
+        assert!(
+            html.contains(r#"href="https://news.example/unsub?a=1&amp;b=2""#)
+        );
+        assert!(html.contains(r#"href="mailto:unsub@news.example""#));
+    }
+}
@@ -0,0 +1,84 @@
+//! # Graceful shutdown
+//!
+//! On SIGINT or SIGTERM the [`ShutdownTrigger`] is pulled, and everything
+//! holding a [`Shutdown`] stops taking on new work: the HTTP server stops
+//! accepting connections, and the SMTP server does too, answering any new
+//! command with 421 while letting transactions in progress finish.
+
+use tokio::sync::watch;
+
+/// Pulled once to tell every [`Shutdown`] it's time to wrap up.
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+/// Waits for the server to start shutting down.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+/// Creates a [`ShutdownTrigger`] along with a [`Shutdown`] that can be
+/// cloned for every task that needs to know.
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger(sender), Shutdown(receiver))
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl Shutdown {
+    /// Resolves as soon as the shutdown is triggered, straight away if it
+    /// already was. A dropped [`ShutdownTrigger`] counts as triggered.
+    pub async fn triggered(&self) {
+        let _ = self.0.clone().wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Resolves on SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::channel;
+
+    #[tokio::test]
+    async fn every_clone_is_told() {
+        let (trigger, shutdown) = channel();
+        let other = shutdown.clone();
+
+        let waiting = tokio::spawn(async move { other.triggered().await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        trigger.trigger();
+        waiting.await.unwrap();
+        // Later calls don't wait at all
+        shutdown.triggered().await;
+    }
+
+    #[tokio::test]
+    async fn dropped_trigger() {
+        let (trigger, shutdown) = channel();
+        drop(trigger);
+
+        shutdown.triggered().await;
+    }
+}
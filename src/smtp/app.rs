@@ -1,76 +1,603 @@
 //! # SMTP server main entry point
 //!
-//! Receives a listener and spawns a green thread for each open connection,
+//! Receives a listener and spawns a green thread for each open connection
+//! the [`Limits`] let in,
 //! uses the [`State`] machine to tease out the newsletter email from the
-//! client, then parses and stores the entry if it's valid.
+//! client (upgrading the connection to TLS if asked to), and hands it over
+//! to the [`Inboxes`], which parse and store the entry if it's valid.
 
-use std::error::Error;
-use tokio::io::BufReader;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, span};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, span, warn};
 
+use crate::config::Config;
 use crate::database::Pool;
-use crate::models::Entry;
-use crate::smtp::state_machine::State;
+use crate::models::{Attachment, Entry, Feed, NewAttachment, Saved};
+use crate::shutdown::Shutdown;
+use crate::smtp::cid;
+use crate::smtp::limits::{Limits, Refusal};
+use crate::smtp::state_machine::{Session, State};
 
+#[derive(Debug, Default)]
 pub struct Email {
-    pub rcpt: String,
-    pub body: String,
+    /// Every accepted recipient, in the order they were given
+    pub rcpts: Vec<String>,
+    /// The raw DATA payload, which isn't necessarily valid UTF-8
+    pub body: Vec<u8>,
 }
 
 pub enum SMTPResult {
     HealthCheck,
-    Success { email: Option<Email> },
+    /// The client issued STARTTLS, so the stream has to be upgraded
+    StartTls,
+    /// The client said its goodbyes, every email on the way was replied to
+    Done,
 }
 
+/// Why an email couldn't be delivered, which tells the client whether it's
+/// worth trying again later (4xx) or not (5xx).
+#[derive(Debug, Error)]
+pub enum DeliveryError {
+    #[error("{0}")]
+    Temporary(String),
+    #[error("{0}")]
+    Permanent(String),
+}
+
+/// Where the SMTP server delivers emails to.
+pub trait Inboxes {
+    /// Whether there's an inbox for `mailbox`, the lowercased local part of
+    /// an address under our own domain without its sub-address tag.
+    async fn exists(&self, mailbox: &str) -> Result<bool, DeliveryError>;
+
+    /// Stores an email for each of its recipients, all of them accepted by
+    /// [`Inboxes::exists`]. Succeeds if at least one of them got it, as a
+    /// single reply can't tell the client which ones didn't.
+    async fn deliver(&self, email: Email) -> Result<(), DeliveryError>;
+}
+
+/// The [`Feed`]s in the database, each entry becoming a new [`Entry`].
+pub struct FeedInboxes<'a> {
+    pub pool: &'a Pool,
+    pub config: &'a Config,
+}
+
+impl Inboxes for FeedInboxes<'_> {
+    async fn exists(&self, mailbox: &str) -> Result<bool, DeliveryError> {
+        Feed::feed_exists(mailbox, self.pool)
+            .await
+            .map_err(|e| DeliveryError::Temporary(e.to_string()))
+    }
+
+    async fn deliver(&self, email: Email) -> Result<(), DeliveryError> {
+        let span = span!(
+            tracing::Level::INFO,
+            "saving_entry",
+            email_rcpts = email.rcpts.join(", ").as_str()
+        );
+        let _guard = span.enter();
+
+        let (entries, mut attachments): (Vec<Entry>, Vec<NewAttachment>) =
+            email
+                .into_entries(self.config)
+                .map_err(DeliveryError::Permanent)?;
+        attachments.retain(|attachment| {
+            match attachment.check(
+                self.config.attachment_max_size,
+                &self.config.attachment_types,
+            ) {
+                Ok(()) => true,
+                Err(reason) => {
+                    warn!("Attachment discarded: {}", reason);
+                    false
+                }
+            }
+        });
+
+        let mut last_error = None;
+        let mut stored = 0;
+        for entry in entries {
+            // Box<dyn Error> isn't Send, so it can't be held across awaits
+            let saved = entry.save(self.pool).await.map_err(|e| e.to_string());
+            match saved {
+                // Whatever came with it was stored the first time around
+                Ok(Saved::Duplicate(id)) => {
+                    info!(
+                        "Email for {} already stored as {}, skipped",
+                        entry.reference, id
+                    );
+                    stored += 1;
+                }
+                Ok(Saved::Inserted(id)) => {
+                    info!("Email stored for {} as {}", entry.reference, entry);
+                    stored += 1;
+                    // The entry's there already, so a missing attachment
+                    // isn't worth having the client send it all again
+                    let mut inline = vec![];
+                    for attachment in &attachments {
+                        match attachment
+                            .save(&entry.reference, id, self.pool)
+                            .await
+                        {
+                            Ok(saved) if saved.content_id.is_some() => {
+                                inline.push(saved)
+                            }
+                            Ok(_) => {}
+                            Err(e) => error!(
+                                "Couldn't INSERT attachment {} for {} ({})",
+                                attachment.filename, entry.reference, e
+                            ),
+                        }
+                    }
+                    if !inline.is_empty() {
+                        self.show_inline(&entry, id, &inline).await;
+                    }
+                    self.name_feed(&entry).await;
+                    // The latest newsletter knows best how to unsubscribe
+                    let unsubscribe = entry.unsubscribe();
+                    if !unsubscribe.is_empty() {
+                        if let Err(e) = unsubscribe
+                            .save_for_feed(&entry.reference, self.pool)
+                            .await
+                        {
+                            error!(
+                                "Couldn't UPDATE unsubscribe link for {} ({})",
+                                entry.reference, e
+                            );
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Couldn't INSERT email {} for {} ({})",
+                        entry, entry.reference, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        match (stored, last_error) {
+            (0, Some(e)) => Err(DeliveryError::Temporary(format!(
+                "Couldn't INSERT email ({})",
+                e
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FeedInboxes<'_> {
+    /// Names a [`Feed`] still titled with a placeholder after the mailing
+    /// list a saved [`Entry`] came through.
+    async fn name_feed(&self, entry: &Entry) {
+        let title = match entry.list_name.as_ref().or(entry.list_id.as_ref()) {
+            Some(title) => title,
+            None => return,
+        };
+
+        match Feed::rename_placeholder(
+            &entry.reference,
+            title,
+            &self.config.feed_placeholder_titles,
+            self.pool,
+        )
+        .await
+        {
+            Ok(true) => info!("Feed {} renamed to {}", entry.reference, title),
+            Ok(false) => {}
+            Err(e) => error!(
+                "Couldn't rename feed {} to {} ({})",
+                entry.reference, title, e
+            ),
+        }
+    }
+
+    /// Points the `cid:` references in a saved [`Entry`] at the URLs its
+    /// `inline` images are served from.
+    async fn show_inline(&self, entry: &Entry, id: i32, inline: &[Attachment]) {
+        let content = cid::rewrite(&entry.content, |content_id| {
+            inline
+                .iter()
+                .find(|attachment| {
+                    attachment
+                        .content_id
+                        .as_deref()
+                        .map(|cid| cid.eq_ignore_ascii_case(content_id))
+                        .unwrap_or(false)
+                })
+                .map(|attachment| attachment.url(&self.config.web_url))
+        });
+
+        if let Err(e) =
+            Entry::set_content(&entry.reference, id, &content, self.pool).await
+        {
+            error!(
+                "Couldn't point {} at its inline images for {} ({})",
+                entry, entry.reference, e
+            );
+        }
+    }
+}
+
+/// Serves SMTP until a shutdown is triggered, then stops accepting
+/// connections and returns once the sessions in progress are over.
 pub async fn serve_smtp(
-    listener: &TcpListener,
+    listener: TcpListener,
     pool: Pool,
-) -> Result<(), Box<dyn Error>> {
+    config: Arc<Config>,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+) {
+    let limits = Arc::new(Limits::new(&config));
+
     loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.triggered() => break,
+        };
+        let (mut socket, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Most likely out of file descriptors, give it a moment
+                error!("Couldn't accept SMTP connection: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let permit = match limits.admit(peer.ip()) {
+            Ok(permit) => permit,
+            Err(refusal) => {
+                warn!(
+                    peer = %peer,
+                    refusal = ?refusal,
+                    active_sessions = limits.active_sessions(),
+                    max_sessions = limits.max_sessions,
+                    "SMTP connection refused"
+                );
+                tokio::spawn(async move {
+                    let reply: &[u8] = match refusal {
+                        Refusal::TooManySessions => {
+                            b"421 4.3.2 Too many connections, try again later\r\n"
+                        }
+                        Refusal::TooManyConnections => {
+                            b"421 4.7.0 Too many connections from your address\r\n"
+                        }
+                    };
+                    let _ = socket.write_all(reply).await;
+                    let _ = socket.shutdown().await;
+                });
+                continue;
+            }
+        };
+
         let pool_arc = pool.clone();
+        let config = config.clone();
+        let limits = limits.clone();
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_smtp_request(&mut socket, &pool_arc).await {
-                error!("SMTP Handler Error: {}", e);
+            let session = Session {
+                peer,
+                config: &config,
+                inboxes: &FeedInboxes {
+                    pool: &pool_arc,
+                    config: &config,
+                },
+                limits: &limits,
+                shutdown: &shutdown,
+                secure: false,
+            };
+            let result =
+                handle_smtp_request(&mut socket, session, tls.as_ref()).await;
+            // Make room for the next session before hanging up
+            drop(permit);
+            if let Err(e) = result {
+                error!(peer = %peer, "SMTP Handler Error: {}", e);
             }
         });
     }
-    #[allow(unreachable_code)] // As we wait for the ! type..
-    Ok(())
+
+    drop(listener);
+    info!(
+        active_sessions = limits.active_sessions(),
+        "SMTP server no longer accepting connections, draining sessions"
+    );
+    limits.drained().await;
 }
 
-async fn handle_smtp_request(
+/// Runs the SMTP session over `stream`, upgrading it to TLS halfway through
+/// if the client asks for it and there's a certificate to do so.
+async fn receive_email<S, I>(
+    stream: S,
+    mut session: Session<'_, I>,
+    tls: Option<&TlsAcceptor>,
+) -> Result<SMTPResult, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    I: Inboxes,
+{
+    let mut stream = BufReader::new(stream);
+
+    match State::Connected.run(&mut stream, &session).await? {
+        SMTPResult::StartTls => {}
+        result => return Ok(result),
+    };
+
+    let acceptor = tls.ok_or("STARTTLS accepted without a certificate")?;
+    // Dropping the read buffer also drops anything the client may have
+    // pipelined after STARTTLS, which RFC 3207 asks us to ignore.
+    let stream = acceptor
+        .accept(stream.into_inner())
+        .await
+        .map_err(|e| format!("TLS handshake failed ({})", e))?;
+    debug!("SMTP connection upgraded to TLS");
+
+    let mut stream = BufReader::new(stream);
+    session.secure = true;
+    let result = State::Connected.run(&mut stream, &session).await;
+    let _ = stream.shutdown().await;
+
+    match result? {
+        SMTPResult::StartTls => Err("STARTTLS issued over TLS".to_owned()),
+        result => Ok(result),
+    }
+}
+
+async fn handle_smtp_request<I: Inboxes>(
     stream: &mut TcpStream,
-    pool: &Pool,
+    session: Session<'_, I>,
+    tls: Option<&TlsAcceptor>,
 ) -> Result<SMTPResult, String> {
-    let mut stream = BufReader::new(stream);
+    let result = receive_email(stream, session, tls).await?;
+    if let SMTPResult::Done = result {
+        debug!("SMTP session ended");
+    }
 
-    let state = State::Connected;
+    Ok(result)
+}
 
-    let envelope: Email = match state.run(&mut stream).await {
-        Ok(SMTPResult::HealthCheck) => return Ok(SMTPResult::HealthCheck),
-        Err(e) => return Err(e),
-        Ok(SMTPResult::Success { email }) => email.unwrap(),
+#[cfg(test)]
+mod tests {
+    use super::{receive_email, serve_smtp, SMTPResult};
+    use crate::config::Config;
+    use crate::database::Pool;
+    use crate::shutdown::{self, Shutdown};
+    use crate::smtp::conformance::TestInboxes;
+    use crate::smtp::limits::Limits;
+    use crate::smtp::state_machine::Session;
+    use crate::smtp::tls;
+    use std::sync::Arc;
+    use tokio::io::{
+        duplex, AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader,
     };
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
 
-    let span = span!(
-        tracing::Level::INFO,
-        "saving_entry",
-        email_rcpt = envelope.rcpt.as_str()
-    );
-    let _guard = span.enter();
+    /// Reads a whole, possibly multi-line, reply.
+    async fn read_reply<R: AsyncBufRead + Unpin>(stream: &mut R) -> String {
+        let mut reply = String::new();
+        loop {
+            let start = reply.len();
+            stream.read_line(&mut reply).await.unwrap();
+            if reply.as_bytes().get(start + 3) != Some(&b'-') {
+                return reply;
+            }
+        }
+    }
 
-    let entry: Entry = match envelope.try_into() {
-        Ok(ent) => ent,
-        Err(e) => return Err(e),
-    };
+    #[tokio::test]
+    async fn starttls_upgrade() {
+        let (acceptor, cert) = tls::tests::acceptor("upgrade");
+        let config = Config {
+            smtp_tls: Some(("cert.pem".into(), "key.pem".into())),
+            ..Config::for_tests()
+        };
+        let (client, server) = duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            let peer = "127.0.0.1:2525".parse().unwrap();
+            let inboxes = TestInboxes::default();
+            let (_trigger, shutdown) = shutdown::channel();
+            let session = Session {
+                peer,
+                config: &config,
+                inboxes: &inboxes,
+                limits: &Limits::new(&config),
+                shutdown: &shutdown,
+                secure: false,
+            };
+            let result = receive_email(server, session, Some(&acceptor)).await;
+            (result, inboxes.delivered())
+        });
+
+        let mut client = BufReader::new(client);
+        assert!(read_reply(&mut client).await.starts_with("220 "));
+        client.write_all(b"EHLO client.example\r\n").await.unwrap();
+        assert!(read_reply(&mut client).await.ends_with("250 STARTTLS\r\n"));
+        client.write_all(b"STARTTLS\r\n").await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with("220 2.0.0"));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let client = connector
+            .connect(
+                ServerName::try_from("ktnrs.com").unwrap(),
+                client.into_inner(),
+            )
+            .await
+            .unwrap();
+        let mut client = BufReader::new(client);
+
+        // The session starts over, without a greeting and without STARTTLS
+        client.write_all(b"EHLO client.example\r\n").await.unwrap();
+        let ehlo = read_reply(&mut client).await;
+        assert!(ehlo.starts_with("250-ktnrs.com"));
+        assert!(!ehlo.contains("STARTTLS"));
+        client
+            .write_all(
+                concat!(
+                    "MAIL FROM:<news@letter.example>\r\n",
+                    "RCPT TO:<abc@ktnrs.com>\r\n",
+                    "DATA\r\n",
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        assert!(read_reply(&mut client).await.starts_with("250 "));
+        assert!(read_reply(&mut client).await.starts_with("250 "));
+        assert!(read_reply(&mut client).await.starts_with("354 "));
+        client
+            .write_all(b"Subject: Secret\r\n\r\nHi\r\n.\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        assert!(read_reply(&mut client).await.starts_with("250 "));
+        assert!(read_reply(&mut client).await.starts_with("221 "));
+
+        let (result, delivered) = server.await.unwrap();
+        assert!(matches!(result, Ok(SMTPResult::Done)));
+        assert_eq!(delivered, ["Subject: Secret\r\n\r\nHi\r\n"]);
+    }
 
-    match entry.save(pool).await {
-        Ok(_) => {
-            info!("Email stored as {}", entry);
-            Ok(SMTPResult::Success { email: None })
+    /// Serves SMTP on a random local port, returning its address. The
+    /// database is never actually connected to.
+    async fn smtp_server(
+        config: Config,
+        shutdown: Shutdown,
+    ) -> (std::net::SocketAddr, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = Pool::connect_lazy(&config.database_url).unwrap();
+        let server = tokio::spawn(serve_smtp(
+            listener,
+            pool,
+            Arc::new(config),
+            None,
+            shutdown,
+        ));
+        (addr, server)
+    }
+
+    async fn connect(addr: std::net::SocketAddr) -> BufReader<TcpStream> {
+        BufReader::new(TcpStream::connect(addr).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn sessions_are_capped() {
+        let (_trigger, shutdown) = shutdown::channel();
+        let (addr, _) = smtp_server(
+            Config {
+                smtp_max_sessions: 1,
+                ..Config::for_tests()
+            },
+            shutdown,
+        )
+        .await;
+
+        let mut first = connect(addr).await;
+        assert!(read_reply(&mut first).await.starts_with("220 "));
+        let mut second = connect(addr).await;
+        assert!(read_reply(&mut second).await.starts_with("421 4.3.2"));
+
+        first.write_all(b"QUIT\r\n").await.unwrap();
+        assert!(read_reply(&mut first).await.starts_with("221 "));
+        assert_eq!(read_reply(&mut first).await, "");
+        let mut third = connect(addr).await;
+        assert!(read_reply(&mut third).await.starts_with("220 "));
+    }
+
+    #[tokio::test]
+    async fn connections_are_rate_limited() {
+        let (_trigger, shutdown) = shutdown::channel();
+        let (addr, _) = smtp_server(
+            Config {
+                smtp_connections_per_minute: 2,
+                ..Config::for_tests()
+            },
+            shutdown,
+        )
+        .await;
+
+        for _ in 0..2 {
+            let mut client = connect(addr).await;
+            assert!(read_reply(&mut client).await.starts_with("220 "));
+        }
+        let mut client = connect(addr).await;
+        assert!(read_reply(&mut client).await.starts_with("421 4.7.0"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_sessions() {
+        let (trigger, shutdown) = shutdown::channel();
+        let (addr, server) = smtp_server(Config::for_tests(), shutdown).await;
+
+        let mut idle = connect(addr).await;
+        assert!(read_reply(&mut idle).await.starts_with("220 "));
+        trigger.trigger();
+
+        // Idle clients are told straight away, and no one else gets in
+        assert!(read_reply(&mut idle).await.starts_with("421 4.3.2"));
+        server.await.unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_transactions_finish() {
+        let config = Config::for_tests();
+        let (trigger, shutdown) = shutdown::channel();
+        let (client, server) = duplex(1 << 16);
+        let server = tokio::spawn(async move {
+            let inboxes = TestInboxes::default();
+            let session = Session {
+                peer: "127.0.0.1:2525".parse().unwrap(),
+                config: &config,
+                inboxes: &inboxes,
+                limits: &Limits::new(&config),
+                shutdown: &shutdown,
+                secure: false,
+            };
+            let result = receive_email(server, session, None).await;
+            (result, inboxes.delivered())
+        });
+
+        let mut client = BufReader::new(client);
+        assert!(read_reply(&mut client).await.starts_with("220 "));
+        client
+            .write_all(
+                concat!(
+                    "HELO client.example\r\n",
+                    "MAIL FROM:<news@letter.example>\r\n",
+                    "RCPT TO:<abc@ktnrs.com>\r\n",
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            assert!(read_reply(&mut client).await.starts_with("250 "));
         }
-        Err(e) => Err(format!("Couldn't INSERT email {} ({})", entry, e)),
+
+        trigger.trigger();
+        client.write_all(b"DATA\r\n").await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with("354 "));
+        client.write_all(b"Subject: Last\r\n.\r\n").await.unwrap();
+        assert!(read_reply(&mut client).await.starts_with("250 "));
+        // No new transaction is waited for
+        assert!(read_reply(&mut client).await.starts_with("421 4.3.2"));
+
+        let (result, delivered) = server.await.unwrap();
+        assert!(matches!(result, Ok(SMTPResult::Done)));
+        assert_eq!(delivered, ["Subject: Last\r\n"]);
     }
 }
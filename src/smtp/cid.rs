@@ -0,0 +1,135 @@
+//! # Inline `cid:` references
+//!
+//! HTML bodies point at the images embedded alongside them in a
+//! `multipart/related` part with `cid:` URLs (RFC 2392), which feed readers
+//! can't do anything with. Once those images are stored, the references are
+//! swapped for the URLs they're served from.
+
+/// Decodes the `%XX` escapes in a `cid:` URL back into its Content-ID.
+fn percent_decode(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The Content-ID in a header value, without its angle brackets.
+pub fn content_id(header: &str) -> Option<String> {
+    let id = header.trim().trim_start_matches('<').trim_end_matches('>');
+    match id.trim() {
+        "" => None,
+        id => Some(id.to_owned()),
+    }
+}
+
+/// Replaces every `cid:` URL in `html`, be it in an attribute or a CSS
+/// `url()`, with the one `url_for` gives its Content-ID, leaving those it
+/// doesn't know about alone.
+pub fn rewrite<F>(html: &str, url_for: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut rewritten = String::with_capacity(html.len());
+    // Lowercasing ASCII leaves byte offsets alone, so this is searched and
+    // `html` sliced with the same ones
+    let lowercase = html.to_ascii_lowercase();
+    let mut offset = 0;
+
+    while let Some(found) = lowercase[offset..].find("cid:") {
+        let start = offset + found;
+        // Only URLs, not the odd "cid:" in the text
+        let quoted = html[..start]
+            .chars()
+            .next_back()
+            .map(|c| "\"'(=".contains(c))
+            .unwrap_or(false);
+        let length = html[start..]
+            .find(|c: char| c.is_whitespace() || "\"'()<>".contains(c))
+            .unwrap_or(html.len() - start);
+        let url = &html[start..start + length];
+
+        rewritten.push_str(&html[offset..start]);
+        match url_for(&percent_decode(&url[4..])) {
+            Some(replacement) if quoted => rewritten.push_str(&replacement),
+            _ => rewritten.push_str(url),
+        }
+        offset = start + length;
+    }
+
+    rewritten.push_str(&html[offset..]);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_id, rewrite};
+
+    fn url_for(cid: &str) -> Option<String> {
+        match cid.to_ascii_lowercase().as_str() {
+            "logo@news" => {
+                Some("https://ktnrs.com/attachments/a/1/l.png".into())
+            }
+            "a b@news" => {
+                Some("https://ktnrs.com/attachments/a/2/b.png".into())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn content_ids() {
+        assert_eq!(content_id(" <logo@news> "), Some("logo@news".to_owned()));
+        assert_eq!(content_id("logo@news"), Some("logo@news".to_owned()));
+        assert_eq!(content_id("<>"), None);
+    }
+
+    #[test]
+    fn image_sources() {
+        assert_eq!(
+            rewrite(
+                r#"<img src="cid:logo@news"><img src='CID:a%20b@news'>"#,
+                url_for
+            ),
+            concat!(
+                r#"<img src="https://ktnrs.com/attachments/a/1/l.png">"#,
+                r#"<img src='https://ktnrs.com/attachments/a/2/b.png'>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn backgrounds() {
+        assert_eq!(
+            rewrite(
+                r#"<td background=cid:logo@news style="background:url(cid:logo@news)">"#,
+                url_for
+            ),
+            concat!(
+                r#"<td background=https://ktnrs.com/attachments/a/1/l.png "#,
+                r#"style="background:url(https://ktnrs.com/attachments/a/1/l.png)">"#,
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_and_textual_references_are_kept() {
+        let html = r#"<p>Write to cid:logo@news</p><img src="cid:other@news">"#;
+        assert_eq!(rewrite(html, url_for), html);
+    }
+}
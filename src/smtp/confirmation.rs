@@ -0,0 +1,256 @@
+//! # Subscription confirmations
+//!
+//! Signing up for a newsletter usually means clicking a link in a "please
+//! confirm your subscription" email first, which is easy to miss among the
+//! rest of a feed. This tells them apart by their wording: it takes both a
+//! subject or a link asking to confirm and a body that says what about,
+//! as either on its own turns up in plenty of other emails. It then digs
+//! out the link to click.
+
+use crate::smtp::trackers::{parse_tag, tag_end};
+
+/// Words asking for an action, in subjects and links.
+const ACTIONS: &[&str] = &["confirm", "verify", "activate", "validate"];
+
+/// What's to be confirmed, in subjects.
+const OBJECTS: &[&str] = &[
+    "subscription",
+    "subscribe",
+    "sign up",
+    "signup",
+    "sign-up",
+    "email",
+    "e-mail",
+    "address",
+    "opt-in",
+];
+
+/// Wording of confirmation emails' bodies, all of it about subscribing:
+/// the likes of "one more step" are just as common in receipts and
+/// password resets.
+const PHRASES: &[&str] = &[
+    "confirm your subscription",
+    "confirm your email",
+    "confirm your e-mail",
+    "confirm your address",
+    "verify your email",
+    "verify your e-mail",
+    "verify your address",
+    "activate your subscription",
+    "click the link below to confirm",
+    "click the button below to confirm",
+    "didn't sign up",
+    "did not sign up",
+    "didn't subscribe",
+    "did not subscribe",
+    "you won't be subscribed",
+    "you will not be subscribed",
+    "double opt-in",
+];
+
+/// Links in confirmation emails that are anything but the one to click.
+const NOT_CONFIRMING: &[&str] = &["unsubscribe", "privacy", "terms"];
+
+/// An email asking to confirm a subscription.
+#[derive(Debug, PartialEq)]
+pub struct Confirmation {
+    /// The link to click to confirm, if one was found
+    pub url: Option<String>,
+}
+
+/// A link in an HTML body, with what it says.
+struct Link {
+    href: String,
+    text: String,
+}
+
+fn contains_any(haystack: &str, needles: &[&str]) -> bool {
+    needles.iter().any(|needle| haystack.contains(needle))
+}
+
+/// The text in `html`, lowercased, with tags turned into spaces and the
+/// apostrophes evened out.
+fn text_of(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if in_tag => {}
+            '\u{2019}' => text.push('\''),
+            c => text.extend(c.to_lowercase()),
+        }
+    }
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Every http(s) link in sanitized `html`.
+fn links(html: &str) -> Vec<Link> {
+    let mut links = vec![];
+    let mut rest = html;
+
+    while let Some(start) = rest.find("<a ") {
+        rest = &rest[start..];
+        let end = match tag_end(rest) {
+            Some(end) => end,
+            None => break,
+        };
+        let (_, attributes) = parse_tag(&rest[1..end - 1]);
+        rest = &rest[end..];
+
+        let close = rest.find("</a>").unwrap_or(rest.len());
+        let text = text_of(&rest[..close]);
+        let href = attributes
+            .into_iter()
+            .find(|(attribute, _)| attribute == "href")
+            .map(|(_, href)| href);
+        if let Some(href) = href {
+            let lowercase = href.to_ascii_lowercase();
+            if lowercase.starts_with("https://")
+                || lowercase.starts_with("http://")
+            {
+                links.push(Link { href, text });
+            }
+        }
+    }
+
+    links
+}
+
+/// The link that confirms the subscription, preferring those that say so
+/// over those whose URL does.
+fn confirmation_link(links: &[Link]) -> Option<&Link> {
+    let confirming = |link: &&Link| {
+        !contains_any(&link.text, NOT_CONFIRMING)
+            && !contains_any(&link.href.to_lowercase(), NOT_CONFIRMING)
+    };
+
+    links
+        .iter()
+        .filter(confirming)
+        .find(|link| contains_any(&link.text, ACTIONS))
+        .or_else(|| {
+            links.iter().filter(confirming).find(|link| {
+                let href = link.href.to_lowercase();
+                contains_any(&href, ACTIONS)
+                    || href.contains("optin")
+                    || href.contains("opt-in")
+            })
+        })
+}
+
+/// Whether an email asks to confirm a subscription, judging by its
+/// `subject` and its sanitized `html` body.
+pub fn classify(subject: &str, html: &str) -> Option<Confirmation> {
+    let subject = subject.to_lowercase();
+    let links = links(html);
+    let link = confirmation_link(&links);
+
+    let asks = (contains_any(&subject, ACTIONS)
+        && contains_any(&subject, OBJECTS))
+        || link.is_some();
+    let says_so = contains_any(&text_of(html), PHRASES);
+
+    (asks && says_so).then(|| Confirmation {
+        url: link.map(|link| link.href.clone()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, Confirmation};
+
+    fn confirmation(url: &str) -> Option<Confirmation> {
+        Some(Confirmation {
+            url: Some(url.to_owned()),
+        })
+    }
+
+    #[test]
+    fn mailchimp() {
+        let html = concat!(
+            "<h1>Please Confirm Subscription</h1>",
+            r#"<p><a href="https://news.us5.list-manage.com/subscribe/confirm?u=a&amp;id=b" rel="noopener noreferrer">"#,
+            "Yes, subscribe me to this list.</a></p>",
+            "<p>If you received this email by mistake, simply delete it. ",
+            "You won\u{2019}t be subscribed if you don\u{2019}t click the ",
+            "confirmation link above.</p>",
+        );
+
+        assert_eq!(
+            classify("Rust Weekly: Please Confirm Subscription", html),
+            confirmation(
+                "https://news.us5.list-manage.com/subscribe/confirm?u=a&id=b"
+            )
+        );
+    }
+
+    #[test]
+    fn substack() {
+        let html = concat!(
+            "<p>Confirm your subscription to The Letter by clicking below.</p>",
+            r#"<a href="https://letter.substack.com/unsubscribe?x=1" rel="noopener noreferrer">Unsubscribe</a>"#,
+            r#"<a href="https://letter.substack.com/api/v1/email/activate?t=abc" rel="noopener noreferrer">"#,
+            "<span>Let's go</span></a>",
+            "<p>If you didn't sign up, you can ignore this email.</p>",
+        );
+
+        assert_eq!(
+            classify("Confirm your email for The Letter", html),
+            confirmation(
+                "https://letter.substack.com/api/v1/email/activate?t=abc"
+            )
+        );
+    }
+
+    #[test]
+    fn without_a_link() {
+        let html = "<p>Reply to this email to confirm your subscription.</p>";
+
+        assert_eq!(
+            classify("Please confirm your subscription", html),
+            Some(Confirmation { url: None })
+        );
+    }
+
+    #[test]
+    fn newsletters_arent_confirmations() {
+        let html = concat!(
+            "<h1>Issue #42</h1><p>We can now confirm the rumours: ",
+            "async closures are here.</p>",
+            r#"<a href="https://news.example/42" rel="noopener noreferrer">Read more</a>"#,
+            r#"<a href="https://news.example/unsubscribe" rel="noopener noreferrer">Unsubscribe</a>"#,
+        );
+
+        assert_eq!(classify("Issue #42: confirmed!", html), None);
+        assert_eq!(classify("Issue #42", html), None);
+    }
+
+    #[test]
+    fn other_emails_arent_confirmations() {
+        // Neither comes with a List-Unsubscribe header, and both have a
+        // link asking to do something
+        let newsletter = concat!(
+            "<h1>Issue #43</h1><p>One more step towards 1.0: we didn't ",
+            "request much of you this year.</p>",
+            r#"<a href="https://news.example/activate" rel="noopener noreferrer">Activate the beta</a>"#,
+        );
+        let receipt = concat!(
+            "<p>You're almost there! Verify your order below.</p>",
+            r#"<a href="https://shop.example/orders/1/verify" rel="noopener noreferrer">Verify</a>"#,
+            "<p>Didn't request this? Let us know.</p>",
+        );
+
+        assert_eq!(classify("Issue #43", newsletter), None);
+        assert_eq!(classify("Verify your order", receipt), None);
+    }
+}
@@ -0,0 +1,1203 @@
+//! # SMTP conformance tests
+//!
+//! Transcripts of whole SMTP sessions, where `C:` lines are sent by the
+//! client and `S:` lines are the replies the server is expected to send.
+//! The client writes all of its lines up front, as a pipelining client
+//! would, and the replies are compared once the session is over.
+//!
+//! Emails are delivered to [`TestInboxes`] rather than the database, but
+//! for those tests that are about what ends up in it.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use crate::config::Config;
+use crate::database::{connect, migrate};
+use crate::models::{Entry, NewFeed};
+use crate::shutdown;
+use crate::smtp::app::{
+    DeliveryError, Email, FeedInboxes, Inboxes, SMTPResult,
+};
+use crate::smtp::limits::Limits;
+use crate::smtp::state_machine::{Session, State};
+
+/// In-memory [`Inboxes`] for a fixed set of feeds that keep what they're
+/// delivered, or fail on demand.
+pub struct TestInboxes {
+    pub feeds: Vec<String>,
+    /// Recipient lookups fail as if the database was down
+    pub lookup_fails: bool,
+    /// Deliveries fail with this error
+    pub failure: Option<DeliveryError>,
+    emails: Mutex<Vec<Email>>,
+}
+
+impl Default for TestInboxes {
+    fn default() -> Self {
+        TestInboxes {
+            feeds: vec!["abc".to_owned(), "def".to_owned()],
+            lookup_fails: false,
+            failure: None,
+            emails: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl TestInboxes {
+    /// The bodies of every email delivered so far.
+    pub fn delivered(&self) -> Vec<String> {
+        let emails = self.emails.lock().unwrap();
+        emails
+            .iter()
+            .map(|email| String::from_utf8_lossy(&email.body).into_owned())
+            .collect()
+    }
+
+    /// The recipients of every email delivered so far.
+    pub fn recipients(&self) -> Vec<Vec<String>> {
+        let emails = self.emails.lock().unwrap();
+        emails.iter().map(|email| email.rcpts.clone()).collect()
+    }
+}
+
+impl Inboxes for TestInboxes {
+    async fn exists(&self, mailbox: &str) -> Result<bool, DeliveryError> {
+        match self.lookup_fails {
+            true => Err(DeliveryError::Temporary("Lookup failed".to_owned())),
+            false => Ok(self.feeds.iter().any(|feed| feed == mailbox)),
+        }
+    }
+
+    async fn deliver(&self, email: Email) -> Result<(), DeliveryError> {
+        match &self.failure {
+            Some(DeliveryError::Temporary(e)) => {
+                Err(DeliveryError::Temporary(e.clone()))
+            }
+            Some(DeliveryError::Permanent(e)) => {
+                Err(DeliveryError::Permanent(e.clone()))
+            }
+            None => {
+                self.emails.lock().unwrap().push(email);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Splits a transcript into what the client sends and what the server is
+/// expected to reply, both with CRLF line endings.
+fn parse_transcript(transcript: &str) -> (String, String) {
+    let mut client = String::new();
+    let mut server = String::new();
+
+    for line in transcript.lines().map(str::trim_start) {
+        if let Some(line) = line.strip_prefix("C:") {
+            client.push_str(line.strip_prefix(' ').unwrap_or(line));
+            client.push_str("\r\n");
+        } else if let Some(line) = line.strip_prefix("S:") {
+            server.push_str(line.strip_prefix(' ').unwrap_or(line));
+            server.push_str("\r\n");
+        }
+    }
+
+    (client, server)
+}
+
+/// Replays the client side of `transcript` against the state machine,
+/// asserts the server replied as expected and returns the session result.
+/// Unless the client `hangs_up` once it's done, it just sits there.
+async fn replay_session<I: Inboxes>(
+    transcript: &str,
+    config: &Config,
+    inboxes: &I,
+    hangs_up: bool,
+) -> Result<SMTPResult, String> {
+    let (input, expected) = parse_transcript(transcript);
+
+    let (mut client, server) = duplex(1 << 20);
+    client.write_all(input.as_bytes()).await.unwrap();
+    if hangs_up {
+        client.shutdown().await.unwrap();
+    }
+
+    let mut server = BufReader::new(server);
+    let (_trigger, shutdown) = shutdown::channel();
+    let session = Session {
+        peer: "127.0.0.1:2525".parse().unwrap(),
+        config,
+        inboxes,
+        limits: &Limits::new(config),
+        shutdown: &shutdown,
+        secure: false,
+    };
+    let result = State::Connected.run(&mut server, &session).await;
+    drop(server);
+
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).await.unwrap();
+    assert_eq!(replies, expected, "Unexpected replies to:\n{}", transcript);
+
+    result
+}
+
+async fn replay_with(
+    transcript: &str,
+    config: &Config,
+    inboxes: &TestInboxes,
+) -> Result<SMTPResult, String> {
+    replay_session(transcript, config, inboxes, true).await
+}
+
+/// Replays `transcript` against the default [`TestInboxes`].
+async fn replay(
+    transcript: &str,
+    config: &Config,
+) -> Result<SMTPResult, String> {
+    replay_with(transcript, config, &TestInboxes::default()).await
+}
+
+/// Replays `transcript` and returns the body of the email it delivered.
+async fn replay_email(transcript: &str, config: &Config) -> Option<String> {
+    let inboxes = TestInboxes::default();
+    let _ = replay_with(transcript, config, &inboxes).await;
+
+    let mut delivered = inboxes.delivered();
+    assert!(delivered.len() <= 1, "More than one email delivered");
+    delivered.pop()
+}
+
+#[tokio::test]
+async fn helo_greeting() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert!(replay_email(transcript, &Config::for_tests())
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn ehlo_greeting() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250 SMTPUTF8
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250 SMTPUTF8
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay(transcript, &Config::for_tests()).await.unwrap();
+}
+
+#[tokio::test]
+async fn commands_are_case_insensitive() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: helo client.example
+        S: 250 ktnrs.com
+        C: mail FROM:<news@letter.example>
+        S: 250 OK
+        C: Rcpt TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: data
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Hi
+        C:
+        C: Hello there
+        C: .
+        S: 250 OK
+        C: quit
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert_eq!(
+        replay_email(transcript, &Config::for_tests())
+            .await
+            .unwrap(),
+        "Subject: Hi\r\n\r\nHello there\r\n"
+    );
+}
+
+#[tokio::test]
+async fn single_transaction() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250 SMTPUTF8
+        C: MAIL FROM:<news@letter.example> BODY=8BITMIME SIZE=60
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Hi
+        C:
+        C: Hello there
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert_eq!(
+        replay_email(transcript, &Config::for_tests())
+            .await
+            .unwrap(),
+        "Subject: Hi\r\n\r\nHello there\r\n"
+    );
+}
+
+#[tokio::test]
+async fn several_transactions() {
+    let inboxes = TestInboxes::default();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: First
+        C: .
+        S: 250 OK
+        C: MAIL FROM:<other@letter.example>
+        S: 250 OK
+        C: RCPT TO:<def@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Second
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+    assert_eq!(
+        inboxes.delivered(),
+        ["Subject: First\r\n", "Subject: Second\r\n"]
+    );
+    assert_eq!(inboxes.recipients(), [["abc@ktnrs.com"], ["def@ktnrs.com"]]);
+}
+
+#[tokio::test]
+async fn rset_clears_the_envelope() {
+    let inboxes = TestInboxes::default();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: RSET
+        S: 250 OK
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: RSET
+        S: 250 OK
+        C: RCPT TO:<def@ktnrs.com>
+        S: 503 5.5.1 Bad sequence of commands
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<def@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Only for def
+        C: .
+        S: 250 OK
+        C: RSET
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+    assert_eq!(inboxes.recipients(), [["def@ktnrs.com"]]);
+}
+
+#[tokio::test]
+async fn greeting_clears_the_envelope() {
+    let inboxes = TestInboxes::default();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250 SMTPUTF8
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: RCPT TO:<def@ktnrs.com>
+        S: 503 5.5.1 Bad sequence of commands
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250 SMTPUTF8
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<def@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Only for def
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+    assert_eq!(inboxes.recipients(), [["def@ktnrs.com"]]);
+}
+
+#[tokio::test]
+async fn too_many_messages() {
+    let mut transcript =
+        "S: 220 ktnrs.com\nC: HELO client.example\n".to_owned();
+    transcript.push_str("S: 250 ktnrs.com\n");
+    for n in 0..100 {
+        transcript.push_str(&format!(
+            concat!(
+                "C: MAIL FROM:<news@letter.example>\nS: 250 OK\n",
+                "C: RCPT TO:<abc@ktnrs.com>\nS: 250 OK\n",
+                "C: DATA\nS: 354 End data with <CR><LF>.<CR><LF>\n",
+                "C: Subject: {}\nC: .\nS: 250 OK\n",
+            ),
+            n
+        ));
+    }
+    transcript.push_str("C: MAIL FROM:<news@letter.example>\n");
+    transcript.push_str("S: 421 4.7.0 Too many messages, closing connection\n");
+    let config = Config {
+        smtp_messages_per_minute: 1000,
+        ..Config::for_tests()
+    };
+    let inboxes = TestInboxes::default();
+
+    replay_with(&transcript, &config, &inboxes).await.unwrap();
+    assert_eq!(inboxes.delivered().len(), 100);
+}
+
+#[tokio::test]
+async fn noop_keeps_the_state() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: NOOP
+        S: 250 OK
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: NOOP
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: NOOP
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: NOOP
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert_eq!(
+        replay_email(transcript, &Config::for_tests())
+            .await
+            .unwrap(),
+        "NOOP\r\n"
+    );
+}
+
+#[tokio::test]
+async fn unrecognized_commands() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: VRFY abc@ktnrs.com
+        S: 500 5.5.2 Command unrecognized
+        C:
+        S: 500 5.5.2 Command unrecognized
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay(transcript, &Config::for_tests()).await.unwrap();
+}
+
+#[tokio::test]
+async fn commands_out_of_sequence() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 503 5.5.1 Bad sequence of commands
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 503 5.5.1 Bad sequence of commands
+        C: DATA
+        S: 503 5.5.1 Bad sequence of commands
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: DATA
+        S: 554 5.5.1 No valid recipients
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Finally
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert_eq!(
+        replay_email(transcript, &Config::for_tests())
+            .await
+            .unwrap(),
+        "Subject: Finally\r\n"
+    );
+}
+
+#[tokio::test]
+async fn too_many_errors() {
+    let mut transcript = "S: 220 ktnrs.com\n".to_owned();
+    for _ in 0..10 {
+        transcript.push_str("C: FOO\nS: 500 5.5.2 Command unrecognized\n");
+    }
+    transcript.push_str("C: QUIT\n");
+    transcript.push_str("S: 421 4.7.0 Too many errors, closing connection\n");
+
+    assert!(replay(&transcript, &Config::for_tests()).await.is_err());
+}
+
+#[tokio::test]
+async fn silent_health_check() {
+    let transcript = "S: 220 ktnrs.com";
+
+    assert!(matches!(
+        replay(transcript, &Config::for_tests()).await,
+        Ok(SMTPResult::HealthCheck)
+    ));
+}
+
+#[tokio::test]
+async fn blank_line_health_check() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C:
+        S: 500 Command Unrecognized
+    "#;
+
+    assert!(matches!(
+        replay(transcript, &Config::for_tests()).await,
+        Ok(SMTPResult::HealthCheck)
+    ));
+}
+
+#[tokio::test]
+async fn hang_up_mid_transaction() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+    "#;
+
+    assert!(replay_email(transcript, &Config::for_tests())
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn hang_up_mid_data() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Cut short
+    "#;
+
+    assert!(replay(transcript, &Config::for_tests()).await.is_err());
+}
+
+#[tokio::test]
+async fn smtputf8_addresses() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<jösé@exämple.com> SMTPUTF8
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay(transcript, &Config::for_tests()).await.unwrap();
+}
+
+#[tokio::test]
+async fn declared_size_over_limit() {
+    let config = Config {
+        smtp_max_message_size: 100,
+        ..Config::for_tests()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example> SIZE=101
+        S: 552 5.3.4 Message size exceeds fixed maximum
+        C: MAIL FROM:<news@letter.example> SIZE=100
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay(transcript, &config).await.unwrap();
+}
+
+#[tokio::test]
+async fn data_over_limit() {
+    let config = Config {
+        smtp_max_message_size: 16,
+        ..Config::for_tests()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: This is way too long
+        C:
+        C: Hello there
+        C: .
+        S: 552 5.3.4 Message size exceeds fixed maximum
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: DATA
+        S: 554 5.5.1 No valid recipients
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert!(replay_email(transcript, &config).await.is_none());
+}
+
+#[tokio::test]
+async fn starttls_unavailable() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250 SMTPUTF8
+        C: STARTTLS
+        S: 454 4.7.0 TLS not available
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay(transcript, &Config::for_tests()).await.unwrap();
+}
+
+#[tokio::test]
+async fn starttls_required() {
+    let config = Config {
+        smtp_tls: Some(("cert.pem".into(), "key.pem".into())),
+        smtp_require_tls: true,
+        ..Config::for_tests()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: EHLO client.example
+        S: 250-ktnrs.com
+        S: 250-SIZE 10485760
+        S: 250-PIPELINING
+        S: 250-8BITMIME
+        S: 250-SMTPUTF8
+        S: 250 STARTTLS
+        C: MAIL FROM:<news@letter.example>
+        S: 530 5.7.0 Must issue a STARTTLS command first
+        C: STARTTLS
+        S: 220 2.0.0 Ready to start TLS
+    "#;
+
+    assert!(matches!(
+        replay(transcript, &config).await,
+        Ok(SMTPResult::StartTls)
+    ));
+}
+
+#[tokio::test]
+async fn unknown_recipients() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<nope@ktnrs.com>
+        S: 550 5.1.1 Mailbox unavailable
+        C: RCPT TO:<abc@elsewhere.example>
+        S: 550 5.7.1 Relaying denied
+        C: RCPT TO:<abc>
+        S: 501 5.1.3 Bad recipient address syntax
+        C: RCPT TO:<ABC@KTNRS.COM>
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay(transcript, &Config::for_tests()).await.unwrap();
+}
+
+#[tokio::test]
+async fn sub_addressed_recipients() {
+    let inboxes = TestInboxes::default();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc+weekly@ktnrs.com>
+        S: 250 OK
+        C: RCPT TO:<nope+weekly@ktnrs.com>
+        S: 550 5.1.1 Mailbox unavailable
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Tagged
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+    assert_eq!(inboxes.recipients(), [["abc+weekly@ktnrs.com"]]);
+}
+
+#[tokio::test]
+async fn one_email_to_several_tags() {
+    // Delivered to the database, as that's where duplicates are caught
+    let config = Config::for_tests();
+    let pool = connect("sqlite::memory:", 1).await.unwrap();
+    migrate(&pool).await.unwrap();
+    let reference = NewFeed {
+        title: "Weekly".to_owned(),
+        reference: Some("abc".to_owned()),
+    }
+    .save(&pool, &config)
+    .await
+    .unwrap();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc+a@ktnrs.com>
+        S: 250 OK
+        C: RCPT TO:<abc+b@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Message-ID: <1@letter.example>
+        C: Subject: Tagged twice
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+    let inboxes = FeedInboxes {
+        pool: &pool,
+        config: &config,
+    };
+
+    replay_session(transcript, &config, &inboxes, true)
+        .await
+        .unwrap();
+    for tag in ["a", "b"] {
+        let entries = Entry::find_by_reference(&reference, Some(tag), &pool)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1, "{}", tag);
+        assert_eq!(entries[0].title, "Tagged twice");
+    }
+}
+
+#[tokio::test]
+async fn no_valid_recipients() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<nope@ktnrs.com>
+        S: 550 5.1.1 Mailbox unavailable
+        C: DATA
+        S: 554 5.5.1 No valid recipients
+        C: Subject: Nobody home
+        S: 500 5.5.2 Command unrecognized
+        C: .
+        S: 500 5.5.2 Command unrecognized
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert!(replay_email(transcript, &Config::for_tests())
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn recipient_lookup_fails() {
+    let inboxes = TestInboxes {
+        lookup_fails: true,
+        ..TestInboxes::default()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 451 4.3.0 Mailbox temporarily unavailable
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn delivery_fails_temporarily() {
+    let inboxes = TestInboxes {
+        failure: Some(DeliveryError::Temporary("Pool timed out".to_owned())),
+        ..TestInboxes::default()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Hi
+        C: .
+        S: 451 4.3.0 Message couldn't be stored, try again later
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+    assert!(inboxes.delivered().is_empty());
+}
+
+#[tokio::test]
+async fn delivery_fails_permanently() {
+    let inboxes = TestInboxes {
+        failure: Some(DeliveryError::Permanent("Unparseable".to_owned())),
+        ..TestInboxes::default()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Hi
+        C: .
+        S: 554 5.6.0 Message couldn't be processed
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn multiple_recipients() {
+    let inboxes = TestInboxes::default();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: RCPT TO:<nope@ktnrs.com>
+        S: 550 5.1.1 Mailbox unavailable
+        C: RCPT TO:<def@ktnrs.com>
+        S: 250 OK
+        C: RCPT TO:<ABC@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: For both
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    replay_with(transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+    assert_eq!(inboxes.delivered(), ["Subject: For both\r\n"]);
+    assert_eq!(inboxes.recipients(), [["abc@ktnrs.com", "def@ktnrs.com"]]);
+}
+
+#[tokio::test]
+async fn too_many_recipients() {
+    let mut transcript = concat!(
+        "S: 220 ktnrs.com\n",
+        "C: HELO client.example\n",
+        "S: 250 ktnrs.com\n",
+        "C: MAIL FROM:<news@letter.example>\n",
+        "S: 250 OK\n",
+    )
+    .to_owned();
+    let mut feeds = vec![];
+    for n in 0..101 {
+        let feed = format!("feed{}", n);
+        transcript.push_str(&format!("C: RCPT TO:<{}@ktnrs.com>\n", feed));
+        feeds.push(feed);
+        transcript.push_str(match n {
+            100 => "S: 452 4.5.3 Too many recipients\n",
+            _ => "S: 250 OK\n",
+        });
+    }
+    transcript.push_str("C: QUIT\nS: 221 2.0.0 Bye\n");
+    let inboxes = TestInboxes {
+        feeds,
+        ..TestInboxes::default()
+    };
+
+    replay_with(&transcript, &Config::for_tests(), &inboxes)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn dot_stuffing() {
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Dots
+        C:
+        C: ..
+        C: ...and more
+        C: Not. a. terminator.
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert_eq!(
+        replay_email(transcript, &Config::for_tests())
+            .await
+            .unwrap(),
+        "Subject: Dots\r\n\r\n.\r\n..and more\r\nNot. a. terminator.\r\n"
+    );
+}
+
+#[tokio::test]
+async fn eight_bit_data() {
+    let inboxes = TestInboxes::default();
+    let (mut client, server) = duplex(1 << 16);
+    // ISO-8859-1, which isn't valid UTF-8
+    client
+        .write_all(
+            b"HELO client.example\r\n\
+            MAIL FROM:<news@letter.example> BODY=8BITMIME\r\n\
+            RCPT TO:<abc@ktnrs.com>\r\n\
+            DATA\r\n\
+            Subject: Caf\xe9\r\n\r\nCaf\xe9 cr\xe8me\r\n.\r\n\
+            QUIT\r\n",
+        )
+        .await
+        .unwrap();
+    client.shutdown().await.unwrap();
+
+    let (_trigger, shutdown) = shutdown::channel();
+    let session = Session {
+        peer: "127.0.0.1:2525".parse().unwrap(),
+        config: &Config::for_tests(),
+        inboxes: &inboxes,
+        limits: &Limits::new(&Config::for_tests()),
+        shutdown: &shutdown,
+        secure: false,
+    };
+    State::Connected
+        .run(&mut BufReader::new(server), &session)
+        .await
+        .unwrap();
+
+    let emails = inboxes.emails.lock().unwrap();
+    assert_eq!(
+        emails[0].body,
+        b"Subject: Caf\xe9\r\n\r\nCaf\xe9 cr\xe8me\r\n"
+    );
+}
+
+#[tokio::test]
+async fn line_too_long() {
+    let transcript = format!(
+        r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Long
+        C:
+        C: {}
+        C: {}
+        C: .
+        S: 500 5.5.2 Line too long
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#,
+        "a".repeat(998),
+        ".".repeat(999)
+    );
+
+    assert!(replay_email(&transcript, &Config::for_tests())
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn command_too_long() {
+    let transcript = format!(
+        r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<{}@ktnrs.com>
+        S: 500 5.5.2 Line too long
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Short
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#,
+        "a".repeat(2000)
+    );
+
+    assert_eq!(
+        replay_email(&transcript, &Config::for_tests())
+            .await
+            .unwrap(),
+        "Subject: Short\r\n"
+    );
+}
+
+#[tokio::test]
+async fn longest_line() {
+    let transcript = format!(
+        r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: {}
+        C: .
+        S: 250 OK
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#,
+        "a".repeat(998),
+    );
+
+    assert_eq!(
+        replay_email(&transcript, &Config::for_tests())
+            .await
+            .unwrap()
+            .len(),
+        1000
+    );
+}
+
+#[tokio::test]
+async fn command_timeout() {
+    let config = Config {
+        smtp_command_timeout: Duration::from_millis(50),
+        ..Config::for_tests()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        S: 421 4.4.2 Timeout, closing connection
+    "#;
+
+    let inboxes = TestInboxes::default();
+
+    let result = replay_session(transcript, &config, &inboxes, false).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn data_timeout() {
+    let config = Config {
+        smtp_data_timeout: Duration::from_millis(50),
+        ..Config::for_tests()
+    };
+    let inboxes = TestInboxes::default();
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: Slowly
+        S: 421 4.4.2 Timeout, closing connection
+    "#;
+
+    let result = replay_session(transcript, &config, &inboxes, false).await;
+    assert!(result.is_err());
+    assert!(inboxes.delivered().is_empty());
+}
+
+#[tokio::test]
+async fn messages_are_rate_limited() {
+    let config = Config {
+        smtp_messages_per_minute: 1,
+        ..Config::for_tests()
+    };
+    let transcript = r#"
+        S: 220 ktnrs.com
+        C: HELO client.example
+        S: 250 ktnrs.com
+        C: MAIL FROM:<news@letter.example>
+        S: 250 OK
+        C: RCPT TO:<abc@ktnrs.com>
+        S: 250 OK
+        C: DATA
+        S: 354 End data with <CR><LF>.<CR><LF>
+        C: Subject: First
+        C: .
+        S: 250 OK
+        C: MAIL FROM:<news@letter.example>
+        S: 451 4.7.1 Too many emails from your address, try again later
+        C: QUIT
+        S: 221 2.0.0 Bye
+    "#;
+
+    assert_eq!(
+        replay_email(transcript, &config).await.unwrap(),
+        "Subject: First\r\n"
+    );
+}
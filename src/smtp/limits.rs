@@ -0,0 +1,243 @@
+//! # SMTP rate limiting
+//!
+//! Keeps a single client from hogging the server: there's a cap on the
+//! number of sessions served at once, and every IP address gets a token
+//! bucket for new connections and another one for emails, each refilling
+//! at a steady per-minute rate up to a burst of the same size.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+
+/// Buckets kept around at most, one per IP address.
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// How often, at most, the buckets are swept for those that have refilled.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why a new connection was turned away.
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    TooManySessions,
+    TooManyConnections,
+}
+
+/// The buckets of a [`TokenBucket`], with when they were last swept.
+struct Buckets {
+    map: HashMap<IpAddr, (f64, Instant)>,
+    last_sweep: Instant,
+}
+
+/// A token bucket per IP address.
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl TokenBucket {
+    pub fn per_minute(rate: u32) -> TokenBucket {
+        TokenBucket {
+            capacity: rate as f64,
+            per_second: rate as f64 / 60.0,
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn refilled(&self, (tokens, updated): (f64, Instant), now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+        (tokens + elapsed * self.per_second).min(self.capacity)
+    }
+
+    /// Takes a token from `ip`'s bucket at `now`, if there's one left.
+    fn take_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if !buckets.map.contains_key(&ip)
+            && buckets.map.len() >= MAX_TRACKED_IPS
+        {
+            self.make_room(&mut buckets, now);
+        }
+
+        let bucket = buckets.map.entry(ip).or_insert((self.capacity, now));
+        let tokens = self.refilled(*bucket, now);
+        match tokens >= 1.0 {
+            true => {
+                *bucket = (tokens - 1.0, now);
+                true
+            }
+            false => false,
+        }
+    }
+
+    /// Forgets the buckets that have refilled, if they haven't been swept in
+    /// a while, and then the oldest tenth of them if that wasn't enough.
+    /// Either way those IPs start over with a full bucket.
+    fn make_room(&self, buckets: &mut Buckets, now: Instant) {
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            buckets.last_sweep = now;
+            buckets.map.retain(|_, bucket| {
+                self.refilled(*bucket, now) < self.capacity
+            });
+        }
+
+        if buckets.map.len() >= MAX_TRACKED_IPS {
+            let mut updated: Vec<Instant> =
+                buckets.map.values().map(|(_, updated)| *updated).collect();
+            let (_, cutoff, _) =
+                updated.select_nth_unstable(MAX_TRACKED_IPS / 10);
+            let cutoff = *cutoff;
+            buckets.map.retain(|_, (_, updated)| *updated > cutoff);
+        }
+    }
+
+    /// Takes a token from `ip`'s bucket, if there's one left.
+    pub fn take(&self, ip: IpAddr) -> bool {
+        self.take_at(ip, Instant::now())
+    }
+
+    /// Tokens left in `ip`'s bucket, rounded down.
+    pub fn available(&self, ip: IpAddr) -> u32 {
+        let buckets = self.buckets.lock().unwrap();
+        match buckets.map.get(&ip) {
+            Some(bucket) => self.refilled(*bucket, Instant::now()) as u32,
+            None => self.capacity as u32,
+        }
+    }
+}
+
+/// Every limit the SMTP server enforces, shared by all of its sessions.
+pub struct Limits {
+    sessions: Arc<Semaphore>,
+    pub max_sessions: usize,
+    pub connections: TokenBucket,
+    pub messages: TokenBucket,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Limits {
+        Limits {
+            sessions: Arc::new(Semaphore::new(config.smtp_max_sessions)),
+            max_sessions: config.smtp_max_sessions,
+            connections: TokenBucket::per_minute(
+                config.smtp_connections_per_minute,
+            ),
+            messages: TokenBucket::per_minute(config.smtp_messages_per_minute),
+        }
+    }
+
+    /// Lets a new connection from `ip` in, as long as there's room for one
+    /// more session and `ip` hasn't been connecting too often. The session
+    /// lasts for as long as the returned permit is held.
+    pub fn admit(&self, ip: IpAddr) -> Result<OwnedSemaphorePermit, Refusal> {
+        let permit = self
+            .sessions
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Refusal::TooManySessions)?;
+
+        match self.connections.take(ip) {
+            true => Ok(permit),
+            false => Err(Refusal::TooManyConnections),
+        }
+    }
+
+    /// Resolves once every session has ended.
+    pub async fn drained(&self) {
+        let _ = self.sessions.acquire_many(self.max_sessions as u32).await;
+    }
+
+    /// Number of sessions being served right now.
+    pub fn active_sessions(&self) -> usize {
+        self.max_sessions - self.sessions.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, Refusal, TokenBucket, MAX_TRACKED_IPS};
+    use crate::config::Config;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let bucket = TokenBucket::per_minute(2);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(bucket.take_at(ip, now));
+        assert!(bucket.take_at(ip, now));
+        assert!(!bucket.take_at(ip, now));
+        assert!(!bucket.take_at(ip, now + Duration::from_secs(29)));
+        assert!(bucket.take_at(ip, now + Duration::from_secs(30)));
+        // It never holds more than its capacity
+        let later = now + Duration::from_secs(3600);
+        assert!(bucket.take_at(ip, later));
+        assert!(bucket.take_at(ip, later));
+        assert!(!bucket.take_at(ip, later));
+    }
+
+    #[test]
+    fn buckets_are_per_ip() {
+        let bucket = TokenBucket::per_minute(1);
+        let now = Instant::now();
+
+        assert!(bucket.take_at("192.0.2.1".parse().unwrap(), now));
+        assert!(!bucket.take_at("192.0.2.1".parse().unwrap(), now));
+        assert!(bucket.take_at("192.0.2.2".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn tracked_ips_are_capped() {
+        let bucket = TokenBucket::per_minute(2);
+        let now = Instant::now();
+
+        // None of these buckets refill before the next sweep
+        for i in 0..MAX_TRACKED_IPS as u32 + 100 {
+            let ip = IpAddr::from((i + 1).to_be_bytes());
+            let at = now + Duration::from_millis(i as u64);
+            assert!(bucket.take_at(ip, at));
+        }
+        assert!(bucket.buckets.lock().unwrap().map.len() <= MAX_TRACKED_IPS);
+    }
+
+    #[test]
+    fn sessions_are_capped() {
+        let limits = Limits::new(&Config {
+            smtp_max_sessions: 2,
+            ..Config::for_tests()
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let first = limits.admit(ip).unwrap();
+        let _second = limits.admit(ip).unwrap();
+        assert_eq!(limits.active_sessions(), 2);
+        assert_eq!(limits.admit(ip).unwrap_err(), Refusal::TooManySessions);
+
+        drop(first);
+        assert!(limits.admit(ip).is_ok());
+    }
+
+    #[test]
+    fn connections_are_rate_limited() {
+        let limits = Limits::new(&Config {
+            smtp_connections_per_minute: 1,
+            ..Config::for_tests()
+        });
+
+        assert!(limits.admit("192.0.2.1".parse().unwrap()).is_ok());
+        assert_eq!(
+            limits.admit("192.0.2.1".parse().unwrap()).unwrap_err(),
+            Refusal::TooManyConnections
+        );
+        assert!(limits.admit("192.0.2.2".parse().unwrap()).is_ok());
+    }
+}
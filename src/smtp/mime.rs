@@ -0,0 +1,349 @@
+//! # MIME tree walking
+//!
+//! Newsletters nest their content in all sorts of ways, the most common
+//! being `multipart/mixed` → `multipart/alternative` → `text/html`, with
+//! `multipart/related` thrown in when images are embedded. This walks the
+//! whole tree, forwarded `message/rfc822` emails included, to find the best
+//! body to show: HTML if there's any, plain text otherwise, along with the
+//! files attached to it.
+
+use mailparse::{parse_mail, DispositionType, MailParseError, ParsedMail};
+
+use crate::models::NewAttachment;
+use crate::smtp::{cid, text};
+
+/// Nesting levels looked into before giving up on a branch of the tree.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BodyKind {
+    /// Plain text, `flowed` as per RFC 3676 if its `format` parameter says
+    /// so, with `delsp` telling whether soft breaks add a space.
+    Plain {
+        flowed: bool,
+        delsp: bool,
+    },
+    Html,
+}
+
+/// A decoded body, along with the kind of text it holds.
+#[derive(Debug, PartialEq)]
+pub struct Body {
+    pub kind: BodyKind,
+    pub content: String,
+}
+
+impl Body {
+    /// The body as HTML, converting it first if it's plain text.
+    pub fn into_html(self) -> String {
+        match self.kind {
+            BodyKind::Html => self.content,
+            BodyKind::Plain { flowed, delsp } => {
+                text::to_html(&self.content, flowed, delsp)
+            }
+        }
+    }
+}
+
+/// Whether a Content-Type parameter is set to `value`, ignoring case.
+fn param_is(part: &ParsedMail, name: &str, value: &str) -> bool {
+    part.ctype
+        .params
+        .get(name)
+        .map(|param| param.trim().eq_ignore_ascii_case(value))
+        .unwrap_or(false)
+}
+
+/// Whether the part is an attachment rather than something to display.
+fn is_attachment(part: &ParsedMail) -> bool {
+    part.get_content_disposition().disposition == DispositionType::Attachment
+}
+
+/// The root of a `multipart/related` part, the one its `start` parameter
+/// points at or else the first one, as per RFC 2387.
+fn related_root<'a, 'b>(
+    part: &'b ParsedMail<'a>,
+) -> Option<&'b ParsedMail<'a>> {
+    let start = part.ctype.params.get("start").map(|start| start.trim());
+
+    start
+        .and_then(|start| {
+            part.subparts.iter().find(|subpart| {
+                subpart
+                    .headers
+                    .iter()
+                    .find(|header| {
+                        header.get_key().eq_ignore_ascii_case("Content-ID")
+                    })
+                    .map(|header| header.get_value().trim() == start)
+                    .unwrap_or(false)
+            })
+        })
+        .or_else(|| part.subparts.first())
+}
+
+fn walk(
+    part: &ParsedMail,
+    depth: usize,
+) -> Result<Option<Body>, MailParseError> {
+    if depth > MAX_DEPTH || is_attachment(part) {
+        return Ok(None);
+    }
+
+    let mimetype = part.ctype.mimetype.to_ascii_lowercase();
+    match mimetype.as_str() {
+        "text/html" => Ok(Some(Body {
+            kind: BodyKind::Html,
+            content: part.get_body()?,
+        })),
+        "text/plain" => Ok(Some(Body {
+            kind: BodyKind::Plain {
+                flowed: param_is(part, "format", "flowed"),
+                delsp: param_is(part, "delsp", "yes"),
+            },
+            content: part.get_body()?,
+        })),
+        "message/rfc822" => {
+            let raw = part.get_body_raw()?;
+            walk(&parse_mail(&raw)?, depth + 1)
+        }
+        "multipart/related" => match related_root(part) {
+            Some(root) => walk(root, depth + 1),
+            None => Ok(None),
+        },
+        // Whether it's alternative, mixed, signed or anything else, the
+        // first HTML body wins, then the first plain text one.
+        mimetype if mimetype.starts_with("multipart/") => {
+            let mut best: Option<Body> = None;
+            for subpart in &part.subparts {
+                match walk(subpart, depth + 1)? {
+                    Some(body) if body.kind == BodyKind::Html => {
+                        return Ok(Some(body))
+                    }
+                    Some(body) if best.is_none() => best = Some(body),
+                    _ => {}
+                }
+            }
+            Ok(best)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Finds the best body to display in a parsed email, if it has any.
+pub fn best_body(email: &ParsedMail) -> Result<Option<Body>, MailParseError> {
+    walk(email, 0)
+}
+
+/// The part's Content-ID, if it has one.
+fn content_id(part: &ParsedMail) -> Option<String> {
+    part.headers
+        .iter()
+        .find(|header| header.get_key().eq_ignore_ascii_case("Content-ID"))
+        .and_then(|header| cid::content_id(&header.get_value()))
+}
+
+/// Whether the part is a file to keep: either an explicit attachment, or
+/// something that can't be displayed as a body, like a PDF, a podcast
+/// episode or an image embedded in the HTML body.
+fn is_file(part: &ParsedMail) -> bool {
+    let mimetype = part.ctype.mimetype.to_ascii_lowercase();
+
+    is_attachment(part)
+        || !(mimetype.starts_with("text/")
+            || mimetype.starts_with("multipart/")
+            || mimetype == "message/rfc822")
+}
+
+fn collect_attachments(
+    part: &ParsedMail,
+    depth: usize,
+    attachments: &mut Vec<NewAttachment>,
+) -> Result<(), MailParseError> {
+    if depth > MAX_DEPTH {
+        return Ok(());
+    }
+
+    let mimetype = part.ctype.mimetype.to_ascii_lowercase();
+    if mimetype.starts_with("multipart/") {
+        for subpart in &part.subparts {
+            collect_attachments(subpart, depth + 1, attachments)?;
+        }
+    } else if mimetype == "message/rfc822" && !is_attachment(part) {
+        let raw = part.get_body_raw()?;
+        collect_attachments(&parse_mail(&raw)?, depth + 1, attachments)?;
+    } else if is_file(part) {
+        let disposition = part.get_content_disposition();
+        let filename = disposition
+            .params
+            .get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .map(String::as_str)
+            .unwrap_or("");
+
+        let mut attachment =
+            NewAttachment::new(filename, &mimetype, part.get_body_raw()?);
+        // Those with a Content-ID are shown within the body instead
+        if !is_attachment(part) {
+            attachment.content_id = content_id(part);
+        }
+        attachments.push(attachment);
+    }
+
+    Ok(())
+}
+
+/// Finds every file attached to a parsed email, wherever it is in the tree,
+/// including the inline ones its HTML body refers to by their Content-ID.
+pub fn attachments(
+    email: &ParsedMail,
+) -> Result<Vec<NewAttachment>, MailParseError> {
+    let mut attachments = vec![];
+    collect_attachments(email, 0, &mut attachments)?;
+    Ok(attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{attachments, best_body, Body, BodyKind};
+    use mailparse::parse_mail;
+
+    fn body_of(eml: &[u8]) -> Option<Body> {
+        best_body(&parse_mail(eml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn mixed_alternative_html() {
+        let body = body_of(include_bytes!(
+            "../../fixtures/emails/mixed_alternative.eml"
+        ))
+        .unwrap();
+
+        assert_eq!(body.kind, BodyKind::Html);
+        assert!(body.content.contains("<h1>This week in Rust</h1>"));
+    }
+
+    #[test]
+    fn related_with_inline_image() {
+        let body = body_of(include_bytes!("../../fixtures/emails/related.eml"))
+            .unwrap();
+
+        assert_eq!(body.kind, BodyKind::Html);
+        assert!(body.content.contains(r#"<img src="cid:logo@news""#));
+    }
+
+    #[test]
+    fn forwarded_message() {
+        let body =
+            body_of(include_bytes!("../../fixtures/emails/forwarded.eml"))
+                .unwrap();
+
+        assert_eq!(body.kind, BodyKind::Html);
+        assert!(body.content.contains("Forwarded issue #42"));
+    }
+
+    #[test]
+    fn plain_text_only() {
+        let body =
+            body_of(include_bytes!("../../fixtures/emails/plain_latin1.eml"))
+                .unwrap();
+
+        assert_eq!(
+            body.kind,
+            BodyKind::Plain {
+                flowed: true,
+                delsp: false
+            }
+        );
+        assert!(body.content.contains("Café crème"));
+    }
+
+    #[test]
+    fn attachments_are_skipped() {
+        let body =
+            body_of(include_bytes!("../../fixtures/emails/attachment.eml"))
+                .unwrap();
+
+        assert_eq!(
+            body.kind,
+            BodyKind::Plain {
+                flowed: false,
+                delsp: false
+            }
+        );
+        assert_eq!(body.content.trim(), "See the attached report.");
+    }
+
+    #[test]
+    fn attachments_are_collected() {
+        let eml = include_bytes!("../../fixtures/emails/podcast.eml");
+        let files = attachments(&parse_mail(eml).unwrap()).unwrap();
+
+        let found: Vec<(&str, &str, usize)> = files
+            .iter()
+            .map(|file| {
+                (
+                    file.filename.as_str(),
+                    file.content_type.as_str(),
+                    file.data.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("episode-42.mp3", "audio/mpeg", 12),
+                ("show_notes.pdf", "application/pdf", 9),
+            ]
+        );
+        assert_eq!(files[1].data, b"%PDF-1.4\n");
+        assert!(files.iter().all(|file| file.content_id.is_none()));
+    }
+
+    #[test]
+    fn inline_images() {
+        let related = include_bytes!("../../fixtures/emails/related.eml");
+        let files = attachments(&parse_mail(related).unwrap()).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].filename, "logo.png");
+        assert_eq!(files[0].content_type, "image/png");
+        assert_eq!(files[0].content_id.as_deref(), Some("logo@news"));
+        assert!(files[0].data.starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn bodies_arent_attachments() {
+        let attached = include_bytes!("../../fixtures/emails/attachment.eml");
+        let files = attachments(&parse_mail(attached).unwrap()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].filename, "report.html");
+        assert_eq!(files[0].content_type, "text/html");
+    }
+
+    #[test]
+    fn no_displayable_body() {
+        let eml = concat!(
+            "Subject: Just a picture\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "iVBORw0KGgo=\r\n",
+        );
+
+        assert!(body_of(eml.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn bad_encoding_is_an_error() {
+        let eml = concat!(
+            "Subject: Broken\r\n",
+            "Content-Type: text/html\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "!!! not base64 !!!\r\n",
+        );
+
+        assert!(best_body(&parse_mail(eml.as_bytes()).unwrap()).is_err());
+    }
+}
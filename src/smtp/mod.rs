@@ -6,5 +6,15 @@
 //! build with Enums and matching. Mercy, I implore.
 
 pub mod app;
+mod cid;
+mod confirmation;
+#[cfg(test)]
+mod conformance;
+pub mod limits;
+mod mime;
 mod parse;
+mod sanitize;
 pub mod state_machine;
+mod text;
+pub mod tls;
+mod trackers;
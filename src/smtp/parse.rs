@@ -1,37 +1,216 @@
 //! # Mail Parsing module
 //!
 //! A bunch of boilerplate to use the `mailparse` crate and extract content,
-//! including HTML if multipart email is found.
+//! preferring HTML wherever it is in the MIME tree (see [`mime`]) and
+//! turning plain text into HTML otherwise (see [`text`](super::text)), and
+//! picking up any attached files on the way.
 //!
 //! Some fun with Traits, for good measure.
 
-use mailparse::{dateparse, parse_mail, MailHeaderMap};
-use regex::Regex;
+use mailparse::{
+    addrparse_header, dateparse, parse_mail, MailAddr, MailHeader,
+    MailHeaderMap, MailParseError,
+};
+use sha2::{Digest, Sha256};
 use tracing::{debug, warn};
 
-use crate::models::Entry;
+use crate::config::Config;
+use crate::models::{Entry, NewAttachment, Unsubscribe};
 use crate::smtp::app::Email;
+use crate::smtp::cid;
+use crate::smtp::confirmation;
+use crate::smtp::mime::{self, Body, BodyKind};
+use crate::smtp::sanitize::sanitize;
+use crate::smtp::trackers;
 use crate::time::Epoch;
-use crate::vars::EMAIL_DOMAIN;
-
-// Yanked blindly from https://emailregex.com/
-const EMAIL_REGEX: &str = concat!(
-    r#"(?:[a-z0-9!#$%&'*+/=?^_`{|}~-]+(?:\.[a-z0-9!#$%&'*+/=?^_`{|}~-]+)"#,
-    r#"*|"(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21\x23-\x5b\x5d-\x7f]|\\[\x01-"#,
-    r#"\x09\x0b\x0c\x0e-\x7f])*")@(?:(?:[a-z0-9](?:[a-z0-9-]*[a-z0-9])?\"#,
-    r#".)+[a-z0-9](?:[a-z0-9-]*[a-z0-9])?|\[(?:(?:25[0-5]|2[0-4][0-9]|[0"#,
-    r#"1]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?|[a-z"#,
-    r#"0-9-]*[a-z0-9]:(?:[\x01-\x08\x0b\x0c\x0e-\x1f\x21-\x5a\x53-\x7f]|"#,
-    r#"\\[\x01-\x09\x0b\x0c\x0e-\x7f])+)\])"#
-);
+
+/// Who sent an email, as far as its From header tells.
+#[derive(Debug, PartialEq)]
+pub struct Author {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+impl Author {
+    /// Parses the first address in a From `header`, falling back to its raw
+    /// value as the name if it isn't a valid address.
+    fn from_header(header: Option<&MailHeader>) -> Author {
+        let header = match header {
+            Some(header) => header,
+            None => {
+                return Author {
+                    name: None,
+                    email: None,
+                }
+            }
+        };
+
+        let first = addrparse_header(header)
+            .ok()
+            .and_then(|addrs| addrs.into_inner().into_iter().next());
+        let (name, email) = match first {
+            Some(MailAddr::Single(info)) => {
+                (info.display_name, Some(info.addr))
+            }
+            Some(MailAddr::Group(group)) => {
+                match group.addrs.into_iter().next() {
+                    Some(info) => (info.display_name, Some(info.addr)),
+                    None => (Some(group.group_name), None),
+                }
+            }
+            None => (Some(header.get_value()), None),
+        };
+
+        let non_empty = |value: String| {
+            let value = value.trim().trim_matches('"').trim().to_owned();
+            (!value.is_empty()).then_some(value)
+        };
+        let email = email.and_then(non_empty);
+        match email {
+            // Without an @ it's no address, but it may do as a name
+            Some(email) if !email.contains('@') => Author {
+                name: name.and_then(non_empty).or(Some(email)),
+                email: None,
+            },
+            email => Author {
+                name: name.and_then(non_empty),
+                email,
+            },
+        }
+    }
+
+    /// What to show as the author: the name, or else the address.
+    pub fn display(&self) -> String {
+        self.name
+            .as_ref()
+            .or(self.email.as_ref())
+            .cloned()
+            .unwrap_or_else(|| "Unknown sender".to_owned())
+    }
+}
+
+/// The mailing list an email was sent through, as per its List-Id
+/// (RFC 2919) and List-Post (RFC 2369) headers.
+#[derive(Debug, Default, PartialEq)]
+pub struct MailingList {
+    /// The list's identifier, without its angle brackets
+    pub id: Option<String>,
+    /// The description in front of the identifier, if any
+    pub name: Option<String>,
+    /// Where to post to the list, unless it says it can't be posted to
+    pub post: Option<String>,
+}
+
+impl MailingList {
+    fn from_headers(headers: &[MailHeader]) -> MailingList {
+        let mut list = MailingList::default();
+
+        if let Some(value) = headers.get_first_value("List-Id") {
+            let (name, id) = match value.rsplit_once('<') {
+                Some((name, id)) => (name, id.split('>').next().unwrap_or("")),
+                // Not as per the RFC, but the whole of it will do
+                None => ("", value.as_str()),
+            };
+            let non_empty = |value: &str| {
+                let value = value.trim().trim_matches('"').trim();
+                (!value.is_empty()).then(|| value.to_owned())
+            };
+            list.id = non_empty(id).map(|id| id.to_lowercase());
+            list.name = list.id.as_ref().and_then(|_| non_empty(name));
+        }
+
+        // "NO" has no angle brackets, so it's left out with the comments
+        list.post = headers.get_first_value("List-Post").and_then(|value| {
+            let (_, uri) = value.split_once('<')?;
+            let (uri, _) = uri.split_once('>')?;
+            let uri: String = uri.split_whitespace().collect();
+            (!uri.is_empty()).then_some(uri)
+        });
+
+        list
+    }
+}
+
+/// Reads how to unsubscribe from the List-Unsubscribe header (RFC 2369),
+/// keeping its first http(s) and `mailto:` URIs, and whether the former can
+/// be POSTed to as per the List-Unsubscribe-Post header (RFC 8058), which
+/// requires it to be https.
+fn unsubscribe_from(headers: &[MailHeader]) -> Unsubscribe {
+    let mut unsubscribe = Unsubscribe::default();
+    let list = match headers.get_first_value("List-Unsubscribe") {
+        Some(list) => list,
+        None => return unsubscribe,
+    };
+
+    for uri in list.split(',') {
+        let uri = uri.trim().trim_start_matches('<').trim_end_matches('>');
+        let uri: String = uri.split_whitespace().collect();
+        let scheme = uri.split_once(':').map(|(scheme, _)| scheme);
+        match scheme.map(str::to_ascii_lowercase).as_deref() {
+            Some("http" | "https") if unsubscribe.url.is_none() => {
+                unsubscribe.url = Some(uri)
+            }
+            Some("mailto") if unsubscribe.mailto.is_none() => {
+                unsubscribe.mailto = Some(uri)
+            }
+            _ => {}
+        }
+    }
+
+    let post = headers
+        .get_first_value("List-Unsubscribe-Post")
+        .map(|post| {
+            post.trim()
+                .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+        })
+        .unwrap_or(false);
+    let https = unsubscribe
+        .url
+        .as_ref()
+        .map(|url| url.get(..8).map(|s| s.eq_ignore_ascii_case("https://")))
+        .unwrap_or(None)
+        .unwrap_or(false);
+    unsubscribe.one_click = post && https;
+
+    unsubscribe
+}
+
+/// Splits the local part of an address into the reference of its feed and
+/// its tag, as in `abc+weekly` for the `weekly` stream of the `abc` feed
+/// (RFC 5233), both lowercased.
+pub fn sub_address(mailbox: &str) -> (String, Option<String>) {
+    let mailbox = mailbox.to_lowercase();
+    match mailbox.split_once('+') {
+        Some((reference, "")) => (reference.to_owned(), None),
+        Some((reference, tag)) => (reference.to_owned(), Some(tag.to_owned())),
+        None => (mailbox, None),
+    }
+}
+
+/// Fingerprints an email by what ends up in its [`Entry`], so a redelivery
+/// is recognized even if it went through different relays on the way.
+fn digest(from: &Author, subject: &str, date: &str, content: &str) -> String {
+    let mut hasher = Sha256::new();
+    for field in [from.email.as_deref().unwrap_or(""), subject, date, content] {
+        hasher.update(field.as_bytes());
+        // Keeps ("ab", "c") and ("a", "bc") apart
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
 
 /// Output struct for the SMTP server, containing all the goodies
 pub struct ParsedEmail {
     pub to: String,
-    pub from: String,
+    pub from: Author,
     pub subject: String,
     pub date: String,
+    /// Always HTML, whatever the email came with
     pub body: String,
+    pub attachments: Vec<NewAttachment>,
+    pub unsubscribe: Unsubscribe,
+    pub message_id: Option<String>,
+    pub list: MailingList,
 }
 
 impl std::fmt::Display for ParsedEmail {
@@ -43,22 +222,18 @@ impl std::fmt::Display for ParsedEmail {
                 r#"" body[..50]: {} }}"#
             ),
             &self.to,
-            &self.from,
+            &self.from.display(),
             &self.subject,
             &self.date,
-            if self.body.len() > 50 {
-                &self.body[..50]
-            } else {
-                &self.body
-            }
+            self.body.chars().take(50).collect::<String>()
         )
     }
 }
 
 /// Takes the slice of unsigned bytes that is the email DATA body and returns
 /// a parsed struct of type `ParsedEmail`
-fn parse_bytes_to_email(email: &[u8]) -> ParsedEmail {
-    let parsed = parse_mail(email).unwrap();
+fn parse_bytes_to_email(email: &[u8]) -> Result<ParsedEmail, MailParseError> {
+    let parsed = parse_mail(email)?;
 
     let subject = parsed
         .headers
@@ -70,30 +245,26 @@ fn parse_bytes_to_email(email: &[u8]) -> ParsedEmail {
         .get_first_value("To")
         .unwrap_or_else(|| "unknown@recipient.mail".to_owned());
 
-    let from = parsed
+    let from = Author::from_header(parsed.headers.get_first_header("From"));
+    let unsubscribe = unsubscribe_from(&parsed.headers);
+    let message_id = parsed
         .headers
-        .get_first_value("From")
-        .unwrap_or_else(|| "unknown@sender.mail".to_owned());
-
-    let mut body = String::new();
-
-    // Get the HTML version or the first one if that one isn't found
-    if !parsed.subparts.is_empty() {
-        for part in 0..parsed.subparts.len() {
-            if parsed.subparts[part]
-                .ctype
-                .mimetype
-                .starts_with("text/html")
-            {
-                body.push_str(&parsed.subparts[part].get_body().unwrap());
-            }
-        }
-        if body.is_empty() {
-            body.push_str(&parsed.subparts[0].get_body().unwrap());
+        .get_first_value("Message-ID")
+        .and_then(|id| cid::content_id(&id));
+    let list = MailingList::from_headers(&parsed.headers);
+
+    let body = mime::best_body(&parsed)?.unwrap_or_else(|| {
+        warn!("No text or HTML body found");
+        Body {
+            kind: BodyKind::Plain {
+                flowed: false,
+                delsp: false,
+            },
+            content: String::new(),
         }
-    } else {
-        body.push_str(&parsed.get_body().unwrap());
-    }
+    });
+    let body = body.into_html();
+    let attachments = mime::attachments(&parsed)?;
 
     let date = Epoch::from(
         dateparse(
@@ -109,59 +280,492 @@ fn parse_bytes_to_email(email: &[u8]) -> ParsedEmail {
 
     debug!("Parsed date: {:#?}", date);
 
-    ParsedEmail {
+    Ok(ParsedEmail {
         to,
         from,
         subject,
         date,
         body,
-    }
+        attachments,
+        unsubscribe,
+        message_id,
+        list,
+    })
 }
 
-impl TryFrom<Email> for Entry {
-    type Error = String;
-    fn try_from(envelope: Email) -> Result<Self, Self::Error> {
-        if envelope.rcpt.is_empty() && envelope.body.is_empty() {
+impl Email {
+    /// Parses the envelope into an [`Entry`] for each feed it was addressed
+    /// to, as long as they're inboxes under the configured `email_domain`,
+    /// along with the files attached to it, which go with every one of them.
+    /// The HTML is sanitized on the way, and stripped of trackers too if
+    /// the `strip_trackers` setting says so.
+    pub fn into_entries(
+        self,
+        config: &Config,
+    ) -> Result<(Vec<Entry>, Vec<NewAttachment>), String> {
+        if self.rcpts.is_empty() || self.body.is_empty() {
             warn!("Empty envelope received and discarded");
             return Err("Empty envelope discarded".to_owned());
         }
 
-        let email_find = Regex::new(EMAIL_REGEX).unwrap();
-        let recipient = match email_find.find(&envelope.rcpt) {
-            Some(m) => m.as_str(),
-            _ => "invalid@email.address",
+        let references = self
+            .rcpts
+            .iter()
+            .map(|rcpt| match rcpt.rsplit_once('@') {
+                Some((mailbox, domain))
+                    if domain.eq_ignore_ascii_case(&config.email_domain) =>
+                {
+                    Ok(sub_address(mailbox))
+                }
+                _ => Err(format!("Email for {} received and discarded", rcpt)),
+            })
+            .collect::<Result<Vec<(String, Option<String>)>, String>>()?;
+
+        debug!("Received email for {}", self.rcpts.join(", "));
+
+        let parsed: ParsedEmail = parse_bytes_to_email(&self.body)
+            .map_err(|e| format!("Couldn't parse email ({})", e))?;
+
+        debug!("Parsed envelope addressed to {}", parsed.to);
+
+        let mut content = sanitize(&parsed.body, config);
+        if config.strip_trackers {
+            content = trackers::strip(&content, trackers::RULES);
+        }
+        let digest =
+            digest(&parsed.from, &parsed.subject, &parsed.date, &content);
+        let confirmation = confirmation::classify(&parsed.subject, &content);
+        if confirmation.is_some() {
+            debug!("Subscription confirmation: {}", parsed.subject);
+        }
+        let sender_domain = parsed
+            .from
+            .email
+            .as_ref()
+            .and_then(|email| email.rsplit_once('@'))
+            .map(|(_, domain)| domain.to_lowercase());
+
+        let entries = references
+            .into_iter()
+            .map(|(reference, tag)| Entry {
+                id: 0, // this won't be used
+                created_at: parsed.date.clone(),
+                reference,
+                title: parsed.subject.clone(),
+                author: parsed.from.display(),
+                author_name: parsed.from.name.clone(),
+                author_email: parsed.from.email.clone(),
+                content: content.clone(),
+                unsubscribe_url: parsed.unsubscribe.url.clone(),
+                unsubscribe_mailto: parsed.unsubscribe.mailto.clone(),
+                unsubscribe_one_click: parsed.unsubscribe.one_click,
+                message_id: parsed.message_id.clone(),
+                digest: Some(digest.clone()),
+                tag,
+                list_id: parsed.list.id.clone(),
+                list_name: parsed.list.name.clone(),
+                list_post: parsed.list.post.clone(),
+                sender_domain: sender_domain.clone(),
+                confirmation: confirmation.is_some(),
+                confirmation_url: confirmation
+                    .as_ref()
+                    .and_then(|confirmation| confirmation.url.clone()),
+            })
+            .collect();
+
+        Ok((entries, parsed.attachments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest, sub_address, unsubscribe_from, Author, MailingList};
+    use crate::config::Config;
+    use crate::models::Unsubscribe;
+    use crate::smtp::app::Email;
+    use mailparse::{parse_mail, MailHeaderMap};
+
+    fn author(from: &str) -> Author {
+        let email = format!("From: {}\r\n\r\nHi\r\n", from);
+        let parsed = parse_mail(email.as_bytes()).unwrap();
+        Author::from_header(parsed.headers.get_first_header("From"))
+    }
+
+    #[test]
+    fn authors() {
+        let cases = [
+            (
+                r#""Doe, Jane" <jane@x.com>"#,
+                Some("Doe, Jane"),
+                Some("jane@x.com"),
+            ),
+            (
+                "Jane Doe <jane@x.com>",
+                Some("Jane Doe"),
+                Some("jane@x.com"),
+            ),
+            ("jane@x.com", None, Some("jane@x.com")),
+            ("<jane@x.com>", None, Some("jane@x.com")),
+            (
+                "=?utf-8?Q?Caf=C3=A9?= <cafe@x.com>",
+                Some("Café"),
+                Some("cafe@x.com"),
+            ),
+            ("Team: Jane <jane@x.com>;", Some("Jane"), Some("jane@x.com")),
+            ("The Newsletter", Some("The Newsletter"), None),
+            ("", None, None),
+        ];
+
+        for (from, name, email) in cases {
+            let author = author(from);
+            assert_eq!(author.name.as_deref(), name, "{}", from);
+            assert_eq!(author.email.as_deref(), email, "{}", from);
+        }
+    }
+
+    #[test]
+    fn author_display_fallbacks() {
+        assert_eq!(
+            author(r#""Doe, Jane" <jane@x.com>"#).display(),
+            "Doe, Jane"
+        );
+        assert_eq!(author("jane@x.com").display(), "jane@x.com");
+        assert_eq!(author("").display(), "Unknown sender");
+    }
+
+    fn unsubscribe(headers: &str) -> Unsubscribe {
+        let email = format!("{}\r\n\r\nHi\r\n", headers);
+        unsubscribe_from(&parse_mail(email.as_bytes()).unwrap().headers)
+    }
+
+    #[test]
+    fn unsubscribe_headers() {
+        assert_eq!(
+            unsubscribe(concat!(
+                "List-Unsubscribe: <mailto:unsub@news.example?subject=bye>,\r\n",
+                " <https://news.example/unsub?u=1&id=2>\r\n",
+                "List-Unsubscribe-Post: List-Unsubscribe=One-Click",
+            )),
+            Unsubscribe {
+                url: Some("https://news.example/unsub?u=1&id=2".to_owned()),
+                mailto: Some("mailto:unsub@news.example?subject=bye".to_owned()),
+                one_click: true,
+            }
+        );
+        assert_eq!(
+            unsubscribe("List-Unsubscribe: <http://news.example/unsub>"),
+            Unsubscribe {
+                url: Some("http://news.example/unsub".to_owned()),
+                mailto: None,
+                one_click: false,
+            }
+        );
+        // One-click is only for https
+        assert!(
+            !unsubscribe(concat!(
+                "List-Unsubscribe: <http://news.example/unsub>\r\n",
+                "List-Unsubscribe-Post: List-Unsubscribe=One-Click",
+            ))
+            .one_click
+        );
+        assert!(unsubscribe("List-Unsubscribe: <ftp://x>").is_empty());
+        assert!(unsubscribe("Subject: Hi").is_empty());
+    }
+
+    fn list(headers: &str) -> MailingList {
+        let email = format!("{}\r\n\r\nHi\r\n", headers);
+        MailingList::from_headers(
+            &parse_mail(email.as_bytes()).unwrap().headers,
+        )
+    }
+
+    #[test]
+    fn mailing_lists() {
+        assert_eq!(
+            list(concat!(
+                "List-Id: \"This Week in Rust\" <TWIR.list.example>\r\n",
+                "List-Post: <mailto:twir@list.example>",
+            )),
+            MailingList {
+                id: Some("twir.list.example".to_owned()),
+                name: Some("This Week in Rust".to_owned()),
+                post: Some("mailto:twir@list.example".to_owned()),
+            }
+        );
+        assert_eq!(
+            list("List-Id: <news.list.example>\r\nList-Post: NO (read only)"),
+            MailingList {
+                id: Some("news.list.example".to_owned()),
+                name: None,
+                post: None,
+            }
+        );
+        assert_eq!(
+            list("List-Id: =?utf-8?Q?Caf=C3=A9?= <cafe.list.example>").name,
+            Some("Café".to_owned())
+        );
+        assert_eq!(list("List-Id: Just a name <>"), MailingList::default());
+        assert_eq!(list("Subject: Hi"), MailingList::default());
+    }
+
+    #[test]
+    fn categories_come_along() {
+        let email = Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: concat!(
+                "From: Jane <Jane@Letter.Example>\r\n",
+                "List-Id: Letters <letters.letter.example>\r\n",
+                "\r\nHello\r\n",
+            )
+            .as_bytes()
+            .to_vec(),
         };
 
-        debug!("Received email for {}", recipient);
+        let (entries, _) = email.into_entries(&Config::for_tests()).unwrap();
+        assert_eq!(
+            entries[0].list_id.as_deref(),
+            Some("letters.letter.example")
+        );
+        assert_eq!(entries[0].list_name.as_deref(), Some("Letters"));
+        assert_eq!(entries[0].sender_domain.as_deref(), Some("letter.example"));
+    }
+
+    #[test]
+    fn confirmations_are_flagged() {
+        let email = |subject: &str| Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: format!(
+                concat!(
+                    "Subject: {}\r\n",
+                    "Content-Type: text/html\r\n\r\n",
+                    "<p>Please confirm your subscription to The Letter.</p>",
+                    "<a href=\"https://letter.example/confirm?t=1\">",
+                    "Confirm</a>",
+                ),
+                subject
+            )
+            .into_bytes(),
+        };
+        let entry = |email: Email| {
+            email
+                .into_entries(&Config::for_tests())
+                .unwrap()
+                .0
+                .remove(0)
+        };
 
-        let parsed: ParsedEmail =
-            parse_bytes_to_email(envelope.body.as_bytes());
+        let confirmation = entry(email("Confirm your subscription"));
+        assert!(confirmation.confirmation);
+        assert_eq!(
+            confirmation.confirmation_url.as_deref(),
+            Some("https://letter.example/confirm?t=1")
+        );
 
-        let parsed_to = match email_find.find(&parsed.to) {
-            Some(m) => m.as_str(),
-            _ => "invalid@email.address",
+        // The body says as much on its own
+        assert!(entry(email("Welcome!")).confirmation);
+    }
+
+    #[test]
+    fn one_entry_per_recipient() {
+        let email = Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned(), "DEF@KTNRS.COM".to_owned()],
+            body: b"Subject: Hi\r\nFrom: news@letter.example\r\n\r\nHello\r\n"
+                .to_vec(),
         };
 
-        debug!("Parsed envelope addressed to {}", parsed_to);
+        let (entries, _) = email.into_entries(&Config::for_tests()).unwrap();
+        let references: Vec<&str> = entries
+            .iter()
+            .map(|entry| entry.reference.as_str())
+            .collect();
+        assert_eq!(references, ["abc", "def"]);
+        assert!(entries.iter().all(|entry| entry.title == "Hi"));
+    }
 
-        let received = Entry {
-            id: 0, // this won't be used
-            created_at: parsed.date,
-            reference: parsed_to.split('@').next().unwrap_or("").to_owned(),
-            title: parsed.subject,
-            author: parsed.from,
-            content: parsed.body,
+    #[test]
+    fn sub_addresses() {
+        let split = |mailbox| {
+            let (reference, tag) = sub_address(mailbox);
+            (reference, tag.as_deref().map(str::to_owned))
         };
 
-        if !(recipient.ends_with(EMAIL_DOMAIN)
-            || parsed.to.ends_with(EMAIL_DOMAIN))
-        {
-            Err(format!(
-                "Email for {} received and discarded. Parsed entry: {}",
-                recipient, received
-            ))
-        } else {
-            Ok(received)
-        }
+        assert_eq!(split("abc123"), ("abc123".to_owned(), None));
+        assert_eq!(
+            split("ABC123+Weekly"),
+            ("abc123".to_owned(), Some("weekly".to_owned()))
+        );
+        assert_eq!(
+            split("abc123+a+b"),
+            ("abc123".to_owned(), Some("a+b".to_owned()))
+        );
+        assert_eq!(split("abc123+"), ("abc123".to_owned(), None));
+    }
+
+    #[test]
+    fn tagged_recipients() {
+        let email = Email {
+            rcpts: vec![
+                "abc+weekly@ktnrs.com".to_owned(),
+                "def@ktnrs.com".to_owned(),
+            ],
+            body: b"Subject: Hi\r\n\r\nHello\r\n".to_vec(),
+        };
+
+        let (entries, _) = email.into_entries(&Config::for_tests()).unwrap();
+        assert_eq!(entries[0].reference, "abc");
+        assert_eq!(entries[0].tag.as_deref(), Some("weekly"));
+        assert_eq!(entries[1].reference, "def");
+        assert_eq!(entries[1].tag, None);
+    }
+
+    #[test]
+    fn foreign_recipients() {
+        let email = Email {
+            rcpts: vec![
+                "abc@ktnrs.com".to_owned(),
+                "abc@else.where".to_owned(),
+            ],
+            body: b"Subject: Hi\r\n\r\nHello\r\n".to_vec(),
+        };
+
+        assert!(email.into_entries(&Config::for_tests()).is_err());
+    }
+
+    #[test]
+    fn nested_html_body() {
+        let email = Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: include_bytes!("../../fixtures/emails/mixed_alternative.eml")
+                .to_vec(),
+        };
+
+        let entry = email
+            .into_entries(&Config::for_tests())
+            .unwrap()
+            .0
+            .remove(0);
+        assert_eq!(entry.title, "This Week in Rust 464");
+        assert!(entry.content.contains("<h1>This week in Rust</h1>"));
+    }
+
+    #[test]
+    fn plain_text_becomes_html() {
+        let email = Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: include_bytes!("../../fixtures/emails/plain_latin1.eml")
+                .to_vec(),
+        };
+
+        let entry = email
+            .into_entries(&Config::for_tests())
+            .unwrap()
+            .0
+            .remove(0);
+        assert!(entry.content.starts_with("<p>"));
+        assert!(entry.content.contains("Café crème"));
+    }
+
+    #[test]
+    fn attachments_come_along() {
+        let email = Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned(), "def@ktnrs.com".to_owned()],
+            body: include_bytes!("../../fixtures/emails/podcast.eml").to_vec(),
+        };
+
+        let (entries, attachments) =
+            email.into_entries(&Config::for_tests()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].content.contains("the answer to everything"));
+        assert_eq!(attachments.len(), 2);
+    }
+
+    #[test]
+    fn trackers_are_optional() {
+        let email = || Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: concat!(
+                "Content-Type: text/html\r\n\r\n",
+                "<p>Hi</p>",
+                "<img src=\"https://x.list-manage.com/track/open.php\">",
+            )
+            .as_bytes()
+            .to_vec(),
+        };
+
+        let (entries, _) = email().into_entries(&Config::for_tests()).unwrap();
+        assert_eq!(entries[0].content, "<p>Hi</p>");
+
+        let config = Config {
+            strip_trackers: false,
+            ..Config::for_tests()
+        };
+        let (entries, _) = email().into_entries(&config).unwrap();
+        assert!(entries[0].content.contains("list-manage.com"));
+    }
+
+    #[test]
+    fn redeliveries_look_the_same() {
+        let email = |received: &str, message_id: &str| Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: format!(
+                concat!(
+                    "Received: {}\r\n",
+                    "Message-ID: {}\r\n",
+                    "From: news@letter.example\r\n",
+                    "Subject: Issue #7\r\n",
+                    "Date: Wed, 5 Oct 2022 06:00:00 +0000\r\n",
+                    "\r\nHello\r\n",
+                ),
+                received, message_id
+            )
+            .into_bytes(),
+        };
+        let entry = |email: Email| {
+            email
+                .into_entries(&Config::for_tests())
+                .unwrap()
+                .0
+                .remove(0)
+        };
+
+        let first = entry(email("from a.example", "<7@letter.example>"));
+        let again = entry(email("from b.example", " <7@letter.example> "));
+        assert_eq!(first.message_id.as_deref(), Some("7@letter.example"));
+        assert_eq!(first.message_id, again.message_id);
+        assert_eq!(first.digest, again.digest);
+        assert_eq!(first.digest.as_ref().map(String::len), Some(64));
+
+        let other = entry(email("from a.example", ""));
+        assert_eq!(other.message_id, None);
+        assert_eq!(first.digest, other.digest);
+    }
+
+    #[test]
+    fn digests_tell_emails_apart() {
+        let from = Author {
+            name: None,
+            email: Some("news@letter.example".to_owned()),
+        };
+
+        assert_ne!(
+            digest(&from, "ab", "0", "c"),
+            digest(&from, "a", "0", "bc")
+        );
+        assert_ne!(
+            digest(&from, "Issue #7", "0", "Hi"),
+            digest(&from, "Issue #7", "1", "Hi")
+        );
+    }
+
+    #[test]
+    fn unparseable_body() {
+        let email = Email {
+            rcpts: vec!["abc@ktnrs.com".to_owned()],
+            body: b"Content-Type: text/html\r\n\
+                Content-Transfer-Encoding: base64\r\n\r\n!!!\r\n"
+                .to_vec(),
+        };
+
+        assert!(email.into_entries(&Config::for_tests()).is_err());
     }
 }
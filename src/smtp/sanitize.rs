@@ -0,0 +1,183 @@
+//! # HTML sanitization
+//!
+//! Newsletters are HTML from whoever got hold of an inbox address, and it
+//! ends up in feed readers and our own pages, so it goes through
+//! [`ammonia`]'s allowlist before being stored: scripts, event handlers,
+//! forms, frames and `javascript:` URLs are dropped. On top of ammonia's
+//! defaults, the presentational tags and attributes that table-based email
+//! layouts rely on are kept, along with inline styles unless the
+//! `html_inline_styles` setting says otherwise.
+
+use ammonia::{Builder, UrlRelative};
+
+use crate::config::Config;
+
+/// Tags email layouts use beyond ammonia's defaults.
+const LAYOUT_TAGS: &[&str] = &["big", "font", "main", "section"];
+
+/// Presentational attributes kept on any tag.
+const LAYOUT_ATTRIBUTES: &[&str] = &[
+    "align", "bgcolor", "border", "class", "dir", "height", "valign", "width",
+];
+
+/// Cleans up `html` as per the allowlist and the `config`uration.
+pub fn sanitize(html: &str, config: &Config) -> String {
+    let extra_tags = config.html_extra_tags.iter().map(String::as_str);
+
+    let mut builder = Builder::default();
+    builder
+        .add_tags(LAYOUT_TAGS)
+        .add_tags(extra_tags.clone())
+        // A tag can't be both allowed and have its contents dropped
+        .rm_clean_content_tags(extra_tags)
+        .add_generic_attributes(LAYOUT_ATTRIBUTES)
+        .add_tag_attributes("font", ["color", "face", "size"])
+        .add_tag_attributes("table", ["cellpadding", "cellspacing"])
+        // Inline images are referred to by Content-ID until they're stored
+        .add_url_schemes(["cid"])
+        // Relative URLs would point at our own domain
+        .url_relative(UrlRelative::Deny);
+    if config.html_inline_styles {
+        builder.add_generic_attributes(["style"]);
+    }
+
+    builder.clean(html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+    use crate::config::Config;
+
+    fn clean(html: &str) -> String {
+        sanitize(html, &Config::for_tests())
+    }
+
+    #[test]
+    fn scripts_are_dropped() {
+        assert_eq!(
+            clean("<p>Hi<script>alert(1)</script></p><SCRIPT SRC=//x.js>"),
+            "<p>Hi</p>"
+        );
+        assert_eq!(
+            clean(r#"<svg onload="alert(1)"><script>alert(1)</script></svg>"#),
+            ""
+        );
+    }
+
+    #[test]
+    fn event_handlers_are_dropped() {
+        assert_eq!(
+            clean(r#"<img src="https://x.example/a.png" onerror="alert(1)">"#),
+            r#"<img src="https://x.example/a.png">"#
+        );
+        assert_eq!(
+            clean(r#"<div onmouseover="steal()">Hover</div>"#),
+            "<div>Hover</div>"
+        );
+    }
+
+    #[test]
+    fn dangerous_urls_are_dropped() {
+        assert_eq!(
+            clean(r#"<a href="javascript:alert(1)">Click</a>"#),
+            r#"<a rel="noopener noreferrer">Click</a>"#
+        );
+        assert_eq!(
+            clean(r#"<a href=" JaVaScRiPt:alert(1)">Click</a>"#),
+            r#"<a rel="noopener noreferrer">Click</a>"#
+        );
+        assert_eq!(clean(r#"<img src="data:text/html,<b>">"#), "<img>");
+        assert_eq!(clean(r#"<img src="/tracker.gif">"#), "<img>");
+    }
+
+    #[test]
+    fn forms_and_frames_are_dropped() {
+        assert_eq!(
+            clean(concat!(
+                r#"<form action="https://evil.example/phish">"#,
+                r#"<input name="password"><button>Log in</button></form>"#,
+                r#"<iframe src="https://evil.example"></iframe>"#,
+                r#"<object data="x.swf"></object><embed src="x.swf">"#,
+            )),
+            "Log in"
+        );
+    }
+
+    #[test]
+    fn document_level_tags_are_dropped() {
+        assert_eq!(
+            clean(concat!(
+                r#"<html><head><meta http-equiv="refresh" content="0;url=x">"#,
+                r#"<base href="https://evil.example/"><style>p{}</style>"#,
+                r#"</head><body><p>Hi</p></body></html>"#,
+            )),
+            "<p>Hi</p>"
+        );
+    }
+
+    #[test]
+    fn layout_is_kept() {
+        let html = concat!(
+            r#"<table width="600" cellpadding="0" align="center" "#,
+            r#"style="background-color:#fff"><tbody><tr>"#,
+            r#"<td valign="top" class="header" style="padding:20px">"#,
+            r#"<font color="red" face="Arial">Hi</font>"#,
+            r#"<img src="cid:logo@news" width="100" alt="Logo">"#,
+            r#"<a href="https://news.example/read">Read</a>"#,
+            r#"</td></tr></tbody></table>"#,
+        );
+
+        assert_eq!(
+            clean(html),
+            html.replace(
+                r#"<a href="https://news.example/read">"#,
+                r#"<a href="https://news.example/read" rel="noopener noreferrer">"#
+            )
+        );
+    }
+
+    #[test]
+    fn configurable() {
+        let config = Config {
+            html_extra_tags: vec!["video".to_owned(), "style".to_owned()],
+            html_inline_styles: false,
+            ..Config::for_tests()
+        };
+
+        assert_eq!(
+            sanitize(
+                r#"<style>p{}</style><video></video><p style="x">Hi</p>"#,
+                &config
+            ),
+            "<style>p{}</style><video></video><p>Hi</p>"
+        );
+    }
+
+    #[test]
+    fn hostile_fixture() {
+        let email = mailparse::parse_mail(include_bytes!(
+            "../../fixtures/emails/hostile.eml"
+        ))
+        .unwrap();
+        let html = clean(&email.subparts[1].get_body().unwrap());
+
+        for hostile in [
+            "<script",
+            "onerror",
+            "onclick",
+            "javascript:",
+            "<form",
+            "<iframe",
+            "<meta",
+            "<base",
+            "<link",
+            "<object",
+            "vbscript:",
+        ] {
+            assert!(!html.to_lowercase().contains(hostile), "{}", hostile);
+        }
+        assert!(html.contains(r#"style="font-family:Georgia""#));
+        assert!(html.contains("Issue #12 is here"));
+    }
+}
@@ -2,22 +2,77 @@
 //!
 //! First and clumsy attempt at building a state machine to keep track of
 //! SMTP back and forth communication. Seems to work for simple cases...
+//!
+//! Every [`State::step`] reads a single command (or the whole DATA payload),
+//! replies to it straight away and returns the [`Event`] it resulted in.
+//! Pipelined commands (RFC 2920) simply wait in the read buffer for their
+//! turn, so they get answered in order.
+//!
+//! The machine doesn't care whether it's talking over plain TCP or TLS, it
+//! only needs to be told whether the connection is `secure` already. On
+//! STARTTLS it hands the stream back so it can be upgraded, then it's run
+//! again from scratch as per RFC 3207.
+//!
+//! Recipients are checked against the [`Inboxes`] as soon as they're given,
+//! and emails are only acknowledged once the [`Inboxes`] have stored them,
+//! so senders always know whether their newsletter made it.
+//!
+//! Connections can be reused for as many transactions as the client likes,
+//! up to [`MAX_MESSAGES`], each one starting over from [`State::Greeted`],
+//! or until the server starts shutting down.
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use std::net::SocketAddr;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use tokio::time::timeout;
 use tracing::{debug, trace};
 
-use crate::smtp::app::{Email, SMTPResult};
-use crate::vars::EMAIL_DOMAIN;
+use crate::config::Config;
+use crate::shutdown::Shutdown;
+use crate::smtp::app::{DeliveryError, Email, Inboxes, SMTPResult};
+use crate::smtp::limits::Limits;
+use crate::smtp::parse::sub_address;
+
+/// Unrecognized or out of sequence commands tolerated before hanging up.
+const MAX_ERRORS: usize = 10;
+
+/// Recipients accepted per transaction, the minimum RFC 5321 asks for.
+const MAX_RECIPIENTS: usize = 100;
+
+/// Longest line allowed in the DATA payload, CRLF included, as per RFC 5321
+/// section 4.5.3.1.6.
+const MAX_LINE_LENGTH: usize = 1000;
+
+/// Longest command line allowed, CRLF included, as per RFC 5321 section
+/// 4.5.3.1.4.
+const MAX_COMMAND_LENGTH: usize = 512;
+
+const TIMEOUT_REPLY: &str = "421 4.4.2 Timeout, closing connection";
+const SHUTDOWN_REPLY: &str = "421 4.3.2 Shutting down, try again later";
+
+/// Transactions accepted per connection before asking the client to
+/// reconnect.
+const MAX_MESSAGES: usize = 100;
 
-#[derive(Debug, PartialEq)]
+/// Everything an SMTP session depends on besides its [`State`] and stream.
+pub struct Session<'a, I> {
+    pub peer: SocketAddr,
+    pub config: &'a Config,
+    pub inboxes: &'a I,
+    pub limits: &'a Limits,
+    pub shutdown: &'a Shutdown,
+    /// Whether the connection has been upgraded through STARTTLS already
+    pub secure: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum State {
     Connected,
     Greeted,
     MailFrom,
     RcptTo,
     Data,
-    Done,
     Failed,
     Quit,
 }
@@ -26,153 +81,407 @@ pub enum State {
 pub enum Event {
     HealthCheck,
     Greeting,
+    StartTls,
     NoTls,
+    TlsRequired,
     MailFrom,
     Recipient { rcpt: String },
+    RcptRefused { rcpt: String, reply: &'static str },
+    BadSyntax,
     Data,
-    EndOfFile { buf: String },
+    EndOfFile { buf: Vec<u8> },
+    TooBig,
+    LineTooLong,
+    Unrecognized { cmd: String },
+    OutOfSequence { cmd: String },
     Fail { cmd: String },
     NoOp,
+    Reset,
+    TooManyMessages,
+    RateLimited,
+    Timeout,
+    ShuttingDown,
     Quit,
 }
 
+/// Returns the value of the RFC 1870 `SIZE=` parameter of a `MAIL FROM`
+/// command, if the client declared one.
+fn declared_size(command: &str) -> Option<usize> {
+    command.split_whitespace().skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.eq_ignore_ascii_case("SIZE") {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the address in the forward-path of a `RCPT TO` command, e.g.
+/// `abc@ktnrs.com` out of `RCPT TO:<abc@ktnrs.com> NOTIFY=NEVER`.
+fn forward_path(command: &str) -> Option<&str> {
+    let (_, path) = command.split_once(':')?;
+    let path = path.trim_start();
+    let address = match path.strip_prefix('<') {
+        Some(path) => path.split_once('>')?.0,
+        None => path.split_whitespace().next()?,
+    };
+    // Drop the obsolete source route, as in `<@relay.example:abc@ktnrs.com>`
+    let address = address.rsplit(':').next()?;
+
+    match address.split_once('@') {
+        Some((mailbox, domain))
+            if !mailbox.is_empty() && !domain.is_empty() =>
+        {
+            Some(address)
+        }
+        _ => None,
+    }
+}
+
+/// Joins the lines of a multi-line reply as per RFC 5321 section 4.2.1,
+/// e.g. `250-first`, `250-second`, `250 last`.
+fn multiline_reply(code: u16, lines: &[String]) -> String {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let separator = if i + 1 == lines.len() { ' ' } else { '-' };
+            format!("{}{}{}", code, separator, line)
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
 impl State {
     pub fn next(self, event: &Event) -> State {
         match (self, event) {
             (State::Connected, Event::Greeting) => State::Greeted,
+            (State::Greeted, Event::StartTls) => State::Connected,
             (state, Event::NoTls) => state,
+            (state, Event::TlsRequired) => state,
             (state, Event::HealthCheck) => state,
             (state, Event::NoOp) => state,
+            (State::Data, Event::TooBig) => State::Greeted,
+            (State::Data, Event::LineTooLong) => State::Greeted,
+            (state, Event::LineTooLong) => state,
+            (state, Event::TooBig) => state,
+            (state, Event::RcptRefused { rcpt: _, reply: _ }) => state,
+            (state, Event::BadSyntax) => state,
+            (state, Event::Unrecognized { cmd: _ }) => state,
+            (state, Event::OutOfSequence { cmd: _ }) => state,
+            (_, Event::Fail { cmd: _ }) => State::Failed,
+            (_, Event::Quit) => State::Quit,
+            (_, Event::TooManyMessages) => State::Quit,
+            (_, Event::Timeout) => State::Quit,
+            (_, Event::ShuttingDown) => State::Quit,
+            (state, Event::RateLimited) => state,
+            // RSET doesn't undo the greeting, nor does it make up for it
+            (State::Connected, Event::Reset) => State::Connected,
+            (State::Data, Event::Reset) => State::Failed,
+            (_, Event::Reset) => State::Greeted,
             (State::Connected, _) => State::Failed,
+            (State::Greeted, Event::Greeting) => State::Greeted,
             (State::Greeted, Event::MailFrom) => State::MailFrom,
             (State::Greeted, _) => State::Failed,
+            // A new greeting mid-transaction clears it, like RSET does
+            (State::MailFrom | State::RcptTo, Event::Greeting) => {
+                State::Greeted
+            }
             (State::MailFrom, Event::Recipient { rcpt: _ }) => State::RcptTo,
+            (State::RcptTo, Event::Recipient { rcpt: _ }) => State::RcptTo,
             (State::MailFrom, _) => State::Failed,
             (State::RcptTo, Event::Data) => State::Data,
             (State::RcptTo, _) => State::Failed,
-            (State::Data, Event::EndOfFile { buf: _ }) => State::Done,
+            (State::Data, Event::EndOfFile { buf: _ }) => State::Greeted,
             (State::Data, _) => State::Failed,
-            (_, Event::Fail { cmd: _ }) => State::Failed,
-            (_, Event::Quit) => State::Quit,
-            (_, _) => State::Quit,
+            (_, _) => State::Failed,
         }
     }
 
-    async fn send_command(
-        stream: &mut BufReader<&mut TcpStream>,
-        command: &str,
-    ) {
+    async fn send_command<S>(stream: &mut S, command: &str)
+    where
+        S: AsyncWrite + Unpin,
+    {
         debug!("Send SMTP command: {}", command);
         let _ = stream
             .write_all(format!("{}\r\n", command).as_bytes())
             .await;
+        let _ = stream.flush().await;
     }
 
-    async fn read_line(
-        stream: &mut BufReader<&mut TcpStream>,
+    /// Reads a command line of at most [`MAX_COMMAND_LENGTH`] bytes into
+    /// `buf`. Longer lines are read to the end and dropped, which is told
+    /// apart by returning `None`.
+    async fn read_line<S>(
+        stream: &mut S,
         buf: &mut String,
-    ) -> Result<(), String> {
-        match stream.read_line(buf).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!(
-                "Line[:20]: {} Error: {} ",
-                buf.get(..std::cmp::min(20, buf.len())).unwrap().to_owned(),
-                e
-            )),
+    ) -> Result<Option<usize>, String>
+    where
+        S: AsyncBufRead + Unpin,
+    {
+        let mut line: Vec<u8> = vec![];
+        let read = (&mut *stream)
+            .take(MAX_COMMAND_LENGTH as u64)
+            .read_until(b'\n', &mut line)
+            .await;
+        match read {
+            Ok(n) if n == MAX_COMMAND_LENGTH && !line.ends_with(b"\n") => {
+                State::skip_line(stream).await.map(|()| None)
+            }
+            Ok(n) => match String::from_utf8(line) {
+                Ok(line) => {
+                    buf.push_str(&line);
+                    Ok(Some(n))
+                }
+                Err(e) => Err(State::read_error(e.as_bytes(), &e)),
+            },
+            Err(e) => Err(State::read_error(&line, &e)),
+        }
+    }
+
+    /// Reads what's left of an overlong line, in chunks, and drops it.
+    async fn skip_line<S>(stream: &mut S) -> Result<(), String>
+    where
+        S: AsyncBufRead + Unpin,
+    {
+        let mut chunk: Vec<u8> = vec![];
+        loop {
+            chunk.clear();
+            let read = (&mut *stream)
+                .take(MAX_LINE_LENGTH as u64)
+                .read_until(b'\n', &mut chunk)
+                .await;
+            match read {
+                Ok(0) => return Ok(()),
+                Ok(_) if chunk.ends_with(b"\n") => return Ok(()),
+                Ok(_) => {}
+                Err(e) => return Err(State::read_error(&chunk, &e)),
+            }
         }
     }
 
+    fn read_error(line: &[u8], e: &dyn std::fmt::Display) -> String {
+        let line = String::from_utf8_lossy(line);
+        format!(
+            "Line[:20]: {} Error: {} ",
+            line.chars().take(20).collect::<String>(),
+            e
+        )
+    }
+
+    /// Reads the DATA payload byte for byte until the lone period that
+    /// signals EOF, undoing the dot-stuffing of lines starting with one.
+    /// Payloads over `max_size` or with lines over [`MAX_LINE_LENGTH`] are
+    /// refused, but still read (and dropped) until the end so the client
+    /// gets our reply once it's done sending.
     #[tracing::instrument(skip_all)]
-    async fn recv_response(
-        &self,
-        stream: &mut BufReader<&mut TcpStream>,
-    ) -> Result<String, String> {
-        let mut buf = String::new();
+    async fn recv_data<S>(stream: &mut S, max_size: usize) -> Event
+    where
+        S: AsyncBufRead + Unpin,
+    {
+        let mut buf: Vec<u8> = vec![];
+        let mut line: Vec<u8> = vec![];
+        let mut line_start = true;
+        let mut too_big = false;
+        let mut too_long = false;
 
-        match *self {
-            // If we're receiving data, we loop until we find the lone period
-            // character that signals EOF, or till the pipe is broken. As we
-            // loop, we push what we get into the main buffer and clear the
-            // local one.
-            State::Data => {
-                let mut loop_buf = String::new();
-                let mut loop_count: usize = 0;
-
-                loop {
-                    match State::read_line(stream, &mut loop_buf).await {
-                        Ok(()) => {}
-                        Err(e) => {
-                            debug!("Failure while reading email DATA");
-                            return Err(e);
-                        }
+        loop {
+            // Lines are read in chunks of at most MAX_LINE_LENGTH bytes, so a
+            // chunk not ending in LF is only the beginning of a long line.
+            line.clear();
+            let read = (&mut *stream)
+                .take(MAX_LINE_LENGTH as u64)
+                .read_until(b'\n', &mut line)
+                .await;
+            match read {
+                Ok(0) => {
+                    debug!("Connection closed while reading email DATA");
+                    return Event::Fail {
+                        cmd: "Connection closed during DATA".to_owned(),
+                    };
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("Failure while reading email DATA");
+                    return Event::Fail {
+                        cmd: format!("Couldn't read DATA ({})", e),
                     };
+                }
+            };
 
-                    match loop_buf.as_str() {
-                        ".\r\n" => {
-                            debug!(
-                                "ESC found. loops={}, len={}, trimmed={}",
-                                loop_count,
-                                buf.len(),
-                                buf.trim().len()
-                            );
-                            break;
-                        }
-                        _ => {
-                            buf.push_str(&loop_buf);
-                            loop_buf.clear();
-                            loop_count += 1;
-                        }
-                    }
+            let starts_line = line_start;
+            line_start = line.ends_with(b"\n");
+            if !line_start && line.len() == MAX_LINE_LENGTH {
+                too_long = true;
+            }
+
+            let content = match (starts_line, line.as_slice()) {
+                (true, b".\r\n" | b".\n") => {
+                    debug!(
+                        "ESC found. len={}, too_big={}, too_long={}",
+                        buf.len(),
+                        too_big,
+                        too_long
+                    );
+                    break;
                 }
+                (true, [b'.', unstuffed @ ..]) => unstuffed,
+                (_, content) => content,
+            };
+
+            if too_big || too_long {
+                continue;
+            }
+            if buf.len() + content.len() > max_size {
+                debug!("DATA over {} bytes, draining the rest", max_size);
+                too_big = true;
+                buf = vec![];
+            } else {
+                buf.extend_from_slice(content);
             }
-            // If we're not receiving DATA, we just read a one-line command
-            _ => State::read_line(stream, &mut buf).await?,
         }
 
-        Ok(buf)
+        match (too_big, too_long) {
+            (true, _) => Event::TooBig,
+            (_, true) => Event::LineTooLong,
+            _ => Event::EndOfFile { buf },
+        }
     }
 
-    // We respond to the latest command based on the current State, then
-    // process the response and generate an event with or without payload
-    async fn step(&self, stream: &mut BufReader<&mut TcpStream>) -> Event {
-        match *self {
-            State::Connected => {
-                State::send_command(stream, &format!("220 {}", EMAIL_DOMAIN))
-                    .await;
-            }
-            State::Greeted | State::MailFrom | State::RcptTo => {
-                State::send_command(stream, "250 OK").await;
-            }
-            State::Data => {
-                State::send_command(
-                    stream,
-                    "354 End data with <CR><LF>.<CR><LF>",
-                )
-                .await;
+    /// The multi-line EHLO reply advertising our ESMTP extensions.
+    fn ehlo_reply(config: &Config, secure: bool) -> String {
+        let mut lines = vec![
+            config.email_domain.to_owned(),
+            format!("SIZE {}", config.smtp_max_message_size),
+            "PIPELINING".to_owned(),
+            "8BITMIME".to_owned(),
+            "SMTPUTF8".to_owned(),
+        ];
+        if config.smtp_tls.is_some() && !secure {
+            lines.push("STARTTLS".to_owned());
+        }
+
+        multiline_reply(250, &lines)
+    }
+
+    /// Turns a recipient down unless it's one of our inboxes.
+    async fn check_recipient<I: Inboxes>(
+        rcpt: String,
+        session: &Session<'_, I>,
+    ) -> Event {
+        let mailbox = match rcpt.rsplit_once('@') {
+            Some((mailbox, domain))
+                if domain
+                    .eq_ignore_ascii_case(&session.config.email_domain) =>
+            {
+                mailbox
             }
-            State::Failed => {
-                State::send_command(stream, "502 Not Implemented").await;
-                return Event::Quit;
+            _ => {
+                return Event::RcptRefused {
+                    rcpt,
+                    reply: "550 5.7.1 Relaying denied",
+                }
             }
-            State::Done => {
-                State::send_command(stream, "250 OK").await;
-                return Event::Quit;
+        };
+
+        // Tags are only a label, the feed's what has to exist
+        let (reference, _) = sub_address(mailbox);
+        match session.inboxes.exists(&reference).await {
+            Ok(true) => Event::Recipient { rcpt },
+            Ok(false) => Event::RcptRefused {
+                rcpt,
+                reply: "550 5.1.1 Mailbox unavailable",
+            },
+            Err(e) => {
+                debug!("Couldn't look up recipient {}: {}", rcpt, e);
+                Event::RcptRefused {
+                    rcpt,
+                    reply: "451 4.3.0 Mailbox temporarily unavailable",
+                }
             }
-            State::Quit => {
-                State::send_command(stream, "250 OK").await;
-                State::send_command(stream, "QUIT ").await;
-                return Event::Quit;
+        }
+    }
+
+    // We read the latest command, reply to it based on the current State and
+    // the event it generates, then return said event with or without payload.
+    // The DATA payload is the exception, as it's replied to once delivered.
+    async fn step<S, I>(
+        &self,
+        stream: &mut S,
+        session: &Session<'_, I>,
+        email: &Email,
+        messages: usize,
+    ) -> Event
+    where
+        S: AsyncBufRead + AsyncWrite + Unpin,
+        I: Inboxes,
+    {
+        let config = session.config;
+        let secure = session.secure;
+
+        if *self == State::Data {
+            let event = match timeout(
+                config.smtp_data_timeout,
+                State::recv_data(stream, config.smtp_max_message_size),
+            )
+            .await
+            {
+                Ok(event) => event,
+                Err(_) => Event::Timeout,
+            };
+            match event {
+                Event::TooBig => {
+                    State::send_command(
+                        stream,
+                        "552 5.3.4 Message size exceeds fixed maximum",
+                    )
+                    .await
+                }
+                Event::LineTooLong => {
+                    State::send_command(stream, "500 5.5.2 Line too long").await
+                }
+                Event::Timeout => {
+                    State::send_command(stream, TIMEOUT_REPLY).await
+                }
+                _ => {}
             }
+            return event;
         }
 
+        // Transactions in progress get to finish when shutting down, but new
+        // ones aren't started, nor are idle clients waited on.
+        let idle = matches!(self, State::Connected | State::Greeted);
         let mut buf = String::new();
-        match self.recv_response(stream).await {
-            Ok(resp) => match *self {
-                State::Data => return Event::EndOfFile { buf: resp },
-                _ => buf.push_str(&resp),
-            },
+        let read = tokio::select! {
+            biased;
+            _ = session.shutdown.triggered(), if idle => None,
+            read = timeout(
+                config.smtp_command_timeout,
+                State::read_line(stream, &mut buf),
+            ) => Some(read),
+        };
+        let Some(read) = read else {
+            State::send_command(stream, SHUTDOWN_REPLY).await;
+            return Event::ShuttingDown;
+        };
+        let Ok(read) = read else {
+            State::send_command(stream, TIMEOUT_REPLY).await;
+            return Event::Timeout;
+        };
+        match read {
+            // Healthcheck so fast the pipe is closed by the time we read
+            Ok(Some(0)) if *self == State::Connected => {
+                return Event::HealthCheck
+            }
+            // Client hung up without saying goodbye
+            Ok(Some(0)) => return Event::Quit,
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                State::send_command(stream, "500 5.5.2 Line too long").await;
+                return Event::LineTooLong;
+            }
             Err(e) => {
-                // Healthcheck so fast the pipe is closed by the time we read
                 if *self == State::Connected
                     && e.contains("Connection reset by peer")
                 {
@@ -184,7 +493,7 @@ impl State {
         };
 
         // No command (TCP healthcheck)
-        if buf.trim().is_empty() {
+        if buf.trim().is_empty() && *self == State::Connected {
             State::send_command(stream, "500 Command Unrecognized").await;
             return Event::HealthCheck;
         }
@@ -196,71 +505,291 @@ impl State {
             buf.trim().len()
         );
 
-        let mut buf = buf.trim().to_string();
-
-        // SMTP clients shouldn't unilaterally request TLS without being
-        // explicitly told ESMTP and STARTTLS is fair game, but some are
-        // pretty cheeky, so:
-        if buf.len() >= 8 && buf[..8].eq_ignore_ascii_case("STARTTLS") {
-            State::send_command(stream, "454 TLS Not available").await;
-            buf.clear();
-            stream.read_line(&mut buf).await.unwrap();
-        }
-
+        let buf = buf.trim();
         let command = buf.split(' ').next().unwrap().to_ascii_uppercase();
-        match command.trim() {
+        let event = match command.as_str() {
             "EHLO" | "HELO" => Event::Greeting,
-            "STARTTLS" => Event::NoTls,
-            "MAIL" => Event::MailFrom,
-            "RCPT" => Event::Recipient { rcpt: buf },
-            "DATA" => Event::Data,
-            "NOOP" => {
-                State::send_command(stream, "250 OK").await;
-                Event::NoOp
+            "STARTTLS" if config.smtp_tls.is_some() && !secure => {
+                Event::StartTls
             }
-            "QUIT" | "RSET" => Event::Quit,
-            _ => match *self {
-                State::Done | State::Quit => Event::Quit,
-                _ => Event::Fail {
-                    cmd: command.trim().to_owned(),
+            // SMTP clients shouldn't unilaterally request TLS without being
+            // explicitly told STARTTLS is fair game, but some are pretty
+            // cheeky, so we just turn them down and carry on.
+            "STARTTLS" => Event::NoTls,
+            "MAIL" if config.smtp_require_tls && !secure => Event::TlsRequired,
+            "MAIL" => match declared_size(buf) {
+                Some(size) if size > config.smtp_max_message_size => {
+                    Event::TooBig
+                }
+                _ => Event::MailFrom,
+            },
+            "RCPT" => match forward_path(buf) {
+                Some(rcpt) => Event::Recipient {
+                    rcpt: rcpt.to_owned(),
                 },
+                None => Event::BadSyntax,
             },
-        }
+            "DATA" => Event::Data,
+            "NOOP" => Event::NoOp,
+            "RSET" => Event::Reset,
+            "QUIT" => Event::Quit,
+            _ => Event::Unrecognized {
+                cmd: command.to_owned(),
+            },
+        };
+        let event = match (self.next(&event), event) {
+            (State::Failed, _) => Event::OutOfSequence {
+                cmd: command.clone(),
+            },
+            (_, Event::MailFrom) if messages >= MAX_MESSAGES => {
+                Event::TooManyMessages
+            }
+            (_, Event::MailFrom)
+                if !session.limits.messages.take(session.peer.ip()) =>
+            {
+                Event::RateLimited
+            }
+            (_, Event::Recipient { rcpt })
+                if email.rcpts.len() >= MAX_RECIPIENTS =>
+            {
+                Event::RcptRefused {
+                    rcpt,
+                    reply: "452 4.5.3 Too many recipients",
+                }
+            }
+            (_, Event::Recipient { rcpt }) => {
+                State::check_recipient(rcpt, session).await
+            }
+            (_, event) => event,
+        };
+
+        let reply = match &event {
+            Event::Unrecognized { cmd: _ } => {
+                "500 5.5.2 Command unrecognized".to_owned()
+            }
+            // Every recipient was turned down, most likely while pipelining
+            Event::OutOfSequence { cmd }
+                if *self == State::MailFrom && cmd == "DATA" =>
+            {
+                "554 5.5.1 No valid recipients".to_owned()
+            }
+            Event::OutOfSequence { cmd: _ } => {
+                "503 5.5.1 Bad sequence of commands".to_owned()
+            }
+            Event::RcptRefused { rcpt: _, reply } => reply.to_string(),
+            Event::BadSyntax => {
+                "501 5.1.3 Bad recipient address syntax".to_owned()
+            }
+            Event::Greeting if command == "EHLO" => {
+                State::ehlo_reply(config, secure)
+            }
+            Event::Greeting => format!("250 {}", config.email_domain),
+            Event::StartTls => "220 2.0.0 Ready to start TLS".to_owned(),
+            Event::NoTls => "454 4.7.0 TLS not available".to_owned(),
+            Event::TlsRequired => {
+                "530 5.7.0 Must issue a STARTTLS command first".to_owned()
+            }
+            Event::TooBig => {
+                "552 5.3.4 Message size exceeds fixed maximum".to_owned()
+            }
+            Event::Data => "354 End data with <CR><LF>.<CR><LF>".to_owned(),
+            Event::TooManyMessages => {
+                "421 4.7.0 Too many messages, closing connection".to_owned()
+            }
+            Event::RateLimited => {
+                "451 4.7.1 Too many emails from your address, try again later"
+                    .to_owned()
+            }
+            Event::Quit => "221 2.0.0 Bye".to_owned(),
+            _ => "250 OK".to_owned(),
+        };
+        State::send_command(stream, &reply).await;
+
+        event
     }
 
-    #[tracing::instrument(skip_all, fields(peer))]
-    pub async fn run(
-        mut self,
-        stream: &mut BufReader<&mut TcpStream>,
-    ) -> Result<SMTPResult, String> {
-        tracing::Span::current().record(
-            "peer",
-            &&stream.get_ref().peer_addr().unwrap().to_string()[..],
-        );
-        let mut email = Email {
-            rcpt: String::new(),
-            body: String::new(),
+    /// Hands the email over to the [`Inboxes`] and replies to the DATA
+    /// command according to how that went.
+    async fn deliver<S, I>(
+        stream: &mut S,
+        session: &Session<'_, I>,
+        email: Email,
+    ) where
+        S: AsyncWrite + Unpin,
+        I: Inboxes,
+    {
+        let reply = match session.inboxes.deliver(email).await {
+            Ok(()) => "250 OK",
+            Err(DeliveryError::Temporary(e)) => {
+                debug!("Temporary delivery failure: {}", e);
+                "451 4.3.0 Message couldn't be stored, try again later"
+            }
+            Err(DeliveryError::Permanent(e)) => {
+                debug!("Permanent delivery failure: {}", e);
+                "554 5.6.0 Message couldn't be processed"
+            }
         };
 
+        State::send_command(stream, reply).await;
+    }
+
+    /// Greets the client and steps through the session until it's over,
+    /// delivering each email as soon as it's received. Sessions over a `secure`
+    /// stream are the continuation of one that issued STARTTLS, so there's
+    /// no greeting.
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            peer = %session.peer,
+            secure = session.secure,
+            active_sessions = session.limits.active_sessions(),
+            max_sessions = session.limits.max_sessions,
+            connection_tokens =
+                session.limits.connections.available(session.peer.ip()),
+            message_tokens =
+                session.limits.messages.available(session.peer.ip()),
+        )
+    )]
+    pub async fn run<S, I>(
+        mut self,
+        stream: &mut S,
+        session: &Session<'_, I>,
+    ) -> Result<SMTPResult, String>
+    where
+        S: AsyncBufRead + AsyncWrite + Unpin,
+        I: Inboxes,
+    {
+        let mut email = Email::default();
+        let mut messages: usize = 0;
+        let mut errors: usize = 0;
+
+        if !session.secure {
+            State::send_command(
+                stream,
+                &format!("220 {}", session.config.email_domain),
+            )
+            .await;
+        }
+
         loop {
-            let event: Event = self.step(stream).await;
+            let event: Event =
+                self.step(stream, session, &email, messages).await;
             self = self.next(&event);
             match event {
                 Event::HealthCheck => {
                     trace!("SMTP Health check");
                     return Ok(SMTPResult::HealthCheck);
                 }
-                Event::Recipient { rcpt } => {
-                    email.rcpt.push_str(rcpt.trim());
+                // Repeating a recipient doesn't get it a second copy
+                Event::Recipient { rcpt }
+                    if !email
+                        .rcpts
+                        .iter()
+                        .any(|known| known.eq_ignore_ascii_case(&rcpt)) =>
+                {
+                    email.rcpts.push(rcpt);
+                }
+                Event::RcptRefused { rcpt, reply } => {
+                    debug!("Refused recipient {} ({})", rcpt, reply);
                 }
                 Event::EndOfFile { buf } => {
-                    email.body.push_str(buf.trim());
+                    email.body = buf;
+                    State::deliver(stream, session, std::mem::take(&mut email))
+                        .await;
+                    messages += 1;
+                }
+                // Either way the transaction is over, so is its envelope
+                Event::Reset | Event::Greeting => email = Email::default(),
+                Event::TooBig | Event::LineTooLong
+                    if self == State::Greeted =>
+                {
+                    email = Email::default()
+                }
+                Event::Unrecognized { cmd } | Event::OutOfSequence { cmd } => {
+                    debug!("Rejected SMTP command {}", cmd);
+                    errors += 1;
+                    if errors >= MAX_ERRORS {
+                        State::send_command(
+                            stream,
+                            "421 4.7.0 Too many errors, closing connection",
+                        )
+                        .await;
+                        return Err(format!("Too many errors, last: {}", cmd));
+                    }
                 }
+                Event::StartTls => return Ok(SMTPResult::StartTls),
                 Event::Fail { cmd } => return Err(cmd),
-                Event::Quit => break,
+                Event::Timeout => {
+                    return Err(format!("Timed out in state {:?}", self))
+                }
+                Event::Quit | Event::TooManyMessages => break,
+                Event::ShuttingDown => {
+                    debug!("SMTP session cut short by shutdown");
+                    break;
+                }
                 _ => {}
             }
         }
-        Ok(SMTPResult::Success { email: Some(email) })
+
+        Ok(SMTPResult::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{declared_size, forward_path, multiline_reply, Event, State};
+    use crate::config::Config;
+
+    #[test]
+    fn size_parameter() {
+        assert_eq!(declared_size("MAIL FROM:<a@b.c> SIZE=1024"), Some(1024));
+        assert_eq!(declared_size("MAIL FROM:<a@b.c> size=1024"), Some(1024));
+        assert_eq!(declared_size("MAIL FROM:<a=b@c.d> BODY=8BITMIME"), None);
+        assert_eq!(declared_size("MAIL FROM:<a@b.c> SIZE=lots"), None);
+    }
+
+    #[test]
+    fn forward_paths() {
+        assert_eq!(forward_path("RCPT TO:<a@b.c>"), Some("a@b.c"));
+        assert_eq!(
+            forward_path("RCPT TO: <a@b.c> NOTIFY=NEVER"),
+            Some("a@b.c")
+        );
+        assert_eq!(forward_path("RCPT TO:a@b.c"), Some("a@b.c"));
+        assert_eq!(forward_path("RCPT TO:<@relay.d:a@b.c>"), Some("a@b.c"));
+        assert_eq!(forward_path("RCPT TO:<postmaster>"), None);
+        assert_eq!(forward_path("RCPT TO:<>"), None);
+        assert_eq!(forward_path("RCPT a@b.c"), None);
+    }
+
+    #[test]
+    fn multiline_replies() {
+        assert_eq!(multiline_reply(250, &["OK".to_owned()]), "250 OK");
+        assert_eq!(
+            multiline_reply(250, &["a".to_owned(), "b".to_owned()]),
+            "250-a\r\n250 b"
+        );
+    }
+
+    #[test]
+    fn transaction_transitions() {
+        let command =
+            format!("RCPT TO:<abc@{}>", Config::for_tests().email_domain);
+        let rcpt = Event::Recipient {
+            rcpt: forward_path(&command).unwrap().to_owned(),
+        };
+
+        assert_eq!(State::Connected.next(&Event::Greeting), State::Greeted);
+        assert_eq!(State::Greeted.next(&Event::MailFrom), State::MailFrom);
+        assert_eq!(State::MailFrom.next(&rcpt), State::RcptTo);
+        assert_eq!(State::RcptTo.next(&rcpt), State::RcptTo);
+        assert_eq!(State::RcptTo.next(&Event::Data), State::Data);
+        assert_eq!(State::Greeted.next(&Event::Data), State::Failed);
+        assert_eq!(State::Greeted.next(&Event::StartTls), State::Connected);
+        assert_eq!(State::RcptTo.next(&Event::NoOp), State::RcptTo);
+        assert_eq!(State::RcptTo.next(&Event::Reset), State::Greeted);
+        assert_eq!(State::RcptTo.next(&Event::Greeting), State::Greeted);
+        assert_eq!(State::Connected.next(&Event::Reset), State::Connected);
+        assert_eq!(State::Data.next(&Event::TooBig), State::Greeted);
     }
 }
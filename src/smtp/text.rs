@@ -0,0 +1,298 @@
+//! # Plain text to HTML
+//!
+//! Feeds declare their entries as HTML, so plain text newsletters are turned
+//! into some: the text is escaped, blank lines separate paragraphs, `>`
+//! quotes become (nested) blockquotes and URLs become links. `format=flowed`
+//! text (RFC 3676) is unwrapped into its paragraphs first.
+
+/// A line of text along with how deeply it's quoted.
+#[derive(Debug, PartialEq)]
+struct Line {
+    depth: usize,
+    text: String,
+}
+
+/// Splits the quote markers off a line, e.g. `> > hi` is `hi` quoted twice.
+/// Flowed text has no spaces between markers and is space-stuffed instead.
+fn unquote(line: &str, flowed: bool) -> Line {
+    let mut depth = 0;
+    let mut rest = line;
+    loop {
+        match rest.strip_prefix('>') {
+            Some(unquoted) => {
+                depth += 1;
+                rest = unquoted;
+            }
+            None if !flowed && depth > 0 && rest.starts_with(" >") => {
+                rest = &rest[1..];
+            }
+            None => break,
+        }
+    }
+
+    // Drop the space following the quote markers, or the space-stuffing
+    let text = match depth > 0 || flowed {
+        true => rest.strip_prefix(' ').unwrap_or(rest),
+        false => rest,
+    };
+
+    Line {
+        depth,
+        text: text.to_owned(),
+    }
+}
+
+/// Splits `text` into its lines, joining those of a flowed paragraph as per
+/// RFC 3676 section 4: a line ending in a space continues on the next one,
+/// as long as it's quoted just as deeply and isn't a signature separator.
+fn lines(text: &str, flowed: bool, delsp: bool) -> Vec<Line> {
+    let mut lines: Vec<Line> = vec![];
+    let mut continued = false;
+
+    for raw in text.lines() {
+        let line = unquote(raw, flowed);
+        let soft = flowed && line.text.ends_with(' ') && line.text != "-- ";
+
+        let mut text = line.text;
+        if soft && delsp {
+            text.pop();
+        }
+
+        match lines.last_mut() {
+            Some(last) if continued && last.depth == line.depth => {
+                last.text.push_str(&text)
+            }
+            _ => lines.push(Line {
+                depth: line.depth,
+                text,
+            }),
+        }
+        continued = soft;
+    }
+
+    lines
+}
+
+/// Escapes `text` into `html`, so it can go in both text and attribute values.
+pub fn escape(text: &str, html: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
+
+/// Length of the URL at the start of `text`, leaving out trailing
+/// punctuation and unbalanced closing parentheses.
+fn url_length(text: &str) -> usize {
+    let end = text
+        .find(|c: char| c.is_whitespace() || "<>\"".contains(c))
+        .unwrap_or(text.len());
+    let mut url = &text[..end];
+
+    loop {
+        let trimmed =
+            url.trim_end_matches(['.', ',', ';', ':', '!', '?', '\'']);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(inner)
+                if inner.matches('(').count()
+                    < trimmed.matches(')').count() =>
+            {
+                inner
+            }
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url.len();
+        }
+        url = trimmed;
+    }
+}
+
+/// Escapes `text` into `html`, turning http(s) URLs into links.
+fn linkify(text: &str, html: &mut String) {
+    let mut rest = text;
+
+    while let Some(start) = ["http://", "https://"]
+        .iter()
+        .filter_map(|scheme| rest.find(scheme))
+        .min()
+    {
+        let length = url_length(&rest[start..]);
+        let (before, url) = rest[..start + length].split_at(start);
+        // Nothing but the scheme isn't much of a link
+        if url.ends_with("://") {
+            escape(&rest[..start + length], html);
+        } else {
+            escape(before, html);
+            html.push_str("<a href=\"");
+            escape(url, html);
+            html.push_str("\">");
+            escape(url, html);
+            html.push_str("</a>");
+        }
+        rest = &rest[start + length..];
+    }
+
+    escape(rest, html);
+}
+
+/// Converts a plain text body into HTML, unwrapping it first if it's
+/// `flowed`, deleting the space before soft line breaks if `delsp` too.
+pub fn to_html(text: &str, flowed: bool, delsp: bool) -> String {
+    let mut html = String::new();
+    let mut depth = 0;
+    let mut in_paragraph = false;
+
+    for line in lines(text, flowed, delsp) {
+        let blank = line.text.trim().is_empty();
+
+        if in_paragraph && (blank || line.depth != depth) {
+            html.push_str("</p>\n");
+            in_paragraph = false;
+        }
+        while depth < line.depth {
+            html.push_str("<blockquote>\n");
+            depth += 1;
+        }
+        while depth > line.depth {
+            html.push_str("</blockquote>\n");
+            depth -= 1;
+        }
+        if blank {
+            continue;
+        }
+
+        match in_paragraph {
+            true => html.push_str("<br>\n"),
+            false => html.push_str("<p>"),
+        }
+        in_paragraph = true;
+        linkify(&line.text, &mut html);
+    }
+
+    if in_paragraph {
+        html.push_str("</p>\n");
+    }
+    for _ in 0..depth {
+        html.push_str("</blockquote>\n");
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lines, to_html, unquote, Line};
+
+    #[test]
+    fn paragraphs_and_line_breaks() {
+        assert_eq!(
+            to_html("Hi there,\r\nhow are you?\r\n\r\n\r\nBye", false, false),
+            "<p>Hi there,<br>\nhow are you?</p>\n<p>Bye</p>\n"
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        assert_eq!(
+            to_html("<script>alert('1 & 2')</script>", false, false),
+            "<p>&lt;script&gt;alert(&#39;1 &amp; 2&#39;)&lt;/script&gt;</p>\n"
+        );
+    }
+
+    #[test]
+    fn quotes_become_blockquotes() {
+        assert_eq!(
+            to_html("Said:\n> one\n> > two\n>\n> three\nOK", false, false),
+            concat!(
+                "<p>Said:</p>\n",
+                "<blockquote>\n<p>one</p>\n",
+                "<blockquote>\n<p>two</p>\n</blockquote>\n",
+                "<p>three</p>\n",
+                "</blockquote>\n",
+                "<p>OK</p>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn urls_become_links() {
+        assert_eq!(
+            to_html(
+                "Read https://ex.com/a?b=1&c=2. Or (http://ex.com/wiki_(x)).",
+                false,
+                false
+            ),
+            concat!(
+                r#"<p>Read <a href="https://ex.com/a?b=1&amp;c=2">"#,
+                r#"https://ex.com/a?b=1&amp;c=2</a>. Or ("#,
+                r#"<a href="http://ex.com/wiki_(x)">http://ex.com/wiki_(x)</a>"#,
+                ").</p>\n",
+            )
+        );
+        assert_eq!(
+            to_html("Not a link: https://", false, false),
+            "<p>Not a link: https://</p>\n"
+        );
+    }
+
+    #[test]
+    fn quote_markers() {
+        assert_eq!(
+            unquote("> > hi", false),
+            Line {
+                depth: 2,
+                text: "hi".to_owned()
+            }
+        );
+        assert_eq!(
+            unquote(">> hi", true),
+            Line {
+                depth: 2,
+                text: "hi".to_owned()
+            }
+        );
+        // Space-stuffed
+        assert_eq!(unquote(" >From", true).text, ">From");
+    }
+
+    #[test]
+    fn flowed_lines_are_joined() {
+        let text = "This is a \r\nflowed para.\r\nHard break.\r\n>Quoted \r\n>too\r\n-- \r\nSig";
+
+        let joined: Vec<(usize, String)> = lines(text, true, false)
+            .into_iter()
+            .map(|line| (line.depth, line.text))
+            .collect();
+        assert_eq!(
+            joined,
+            [
+                (0, "This is a flowed para.".to_owned()),
+                (0, "Hard break.".to_owned()),
+                (1, "Quoted too".to_owned()),
+                (0, "-- ".to_owned()),
+                (0, "Sig".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn flowed_delsp() {
+        let joined = lines("Ger \r\nman", true, true);
+        assert_eq!(joined[0].text, "German");
+    }
+
+    #[test]
+    fn flowed_lines_of_different_depths_arent_joined() {
+        assert_eq!(
+            to_html(">quoted \r\nnot quoted", true, false),
+            "<blockquote>\n<p>quoted </p>\n</blockquote>\n<p>not quoted</p>\n"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! # STARTTLS support
+//!
+//! Builds the rustls acceptor SMTP connections are upgraded with once the
+//! client issues STARTTLS (RFC 3207), using the locally configured
+//! certificate chain and private key.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Config;
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Couldn't read {path:?} ({source})")]
+    CouldNotRead {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("No PEM certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+    #[error("No PEM private key found in {0:?}")]
+    NoPrivateKey(PathBuf),
+    #[error("Invalid certificate or private key ({0})")]
+    Invalid(#[from] rustls::Error),
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path).map(BufReader::new).map_err(|source| {
+        TlsError::CouldNotRead {
+            path: path.to_owned(),
+            source,
+        }
+    })
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|source| TlsError::CouldNotRead {
+            path: path.to_owned(),
+            source,
+        })?;
+
+    match certs.is_empty() {
+        true => Err(TlsError::NoCertificates(path.to_owned())),
+        false => Ok(certs),
+    }
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|source| TlsError::CouldNotRead {
+            path: path.to_owned(),
+            source,
+        })?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_owned()))
+}
+
+/// Builds a [`TlsAcceptor`] off a PEM certificate chain and private key.
+pub fn load_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, TlsError> {
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(read_certs(cert)?, read_private_key(key)?)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Returns the [`TlsAcceptor`] for the configured certificate, if any, so
+/// it's only loaded once at startup.
+pub fn tls_acceptor(config: &Config) -> Result<Option<TlsAcceptor>, TlsError> {
+    match &config.smtp_tls {
+        Some((cert, key)) => load_acceptor(cert, key).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{load_acceptor, TlsError};
+    use std::path::PathBuf;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Writes a freshly generated self-signed certificate for `ktnrs.com`
+    /// and its key to a temporary folder, returning both paths and the
+    /// certificate so clients can trust it.
+    pub fn self_signed(
+        name: &str,
+    ) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let generated =
+            rcgen::generate_simple_self_signed(vec!["ktnrs.com".to_owned()])
+                .unwrap();
+
+        let folder = std::env::temp_dir().join(format!(
+            "ktn-tls-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::create_dir_all(&folder).unwrap();
+        let cert = folder.join("cert.pem");
+        let key = folder.join("key.pem");
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        (cert, key, generated.cert.der().clone())
+    }
+
+    /// A [`TlsAcceptor`] for a self-signed certificate along with said
+    /// certificate.
+    pub fn acceptor(name: &str) -> (TlsAcceptor, CertificateDer<'static>) {
+        let (cert, key, der) = self_signed(name);
+        (load_acceptor(&cert, &key).unwrap(), der)
+    }
+
+    #[test]
+    fn loads_self_signed_certificate() {
+        let (cert, key, _) = self_signed("loads");
+
+        assert!(load_acceptor(&cert, &key).is_ok());
+    }
+
+    #[test]
+    fn key_and_certificate_swapped() {
+        let (cert, key, _) = self_signed("swapped");
+
+        assert!(matches!(
+            load_acceptor(&key, &cert),
+            Err(TlsError::NoCertificates(_))
+        ));
+    }
+
+    #[test]
+    fn missing_files() {
+        assert!(matches!(
+            load_acceptor("nope.pem".as_ref(), "nope.key".as_ref()),
+            Err(TlsError::CouldNotRead { .. })
+        ));
+    }
+}
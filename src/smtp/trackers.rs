@@ -0,0 +1,415 @@
+//! # Tracker stripping
+//!
+//! Newsletters phone home whenever they're opened, through tiny beacon
+//! images, and whenever a link is followed, through redirects. Reading them
+//! in a feed reader is meant to be more private than that, so once the HTML
+//! is sanitized, beacons are dropped and redirects that carry their
+//! destination in the URL are swapped for it.
+//!
+//! Each provider is described by a [`Rule`] in [`RULES`], so supporting a new
+//! one is a matter of adding it there, along with a test.
+
+use tracing::debug;
+use url::Url;
+
+use crate::smtp::text::escape;
+
+/// Redirects followed within a single link, as they can be nested.
+const MAX_REDIRECTS: usize = 5;
+
+/// What to do with the URLs a [`Rule`] matches.
+#[derive(Debug)]
+pub enum Action {
+    /// Drop images pointing at it, as they only track opens
+    Beacon,
+    /// Link straight to the URL in the `param` query parameter
+    Redirect { param: &'static str },
+}
+
+/// URLs of a tracking provider: those on `domain` (or its subdomains) with
+/// `path` as their path, or below it. Paths ending in `/` match anything
+/// below them instead, so `/o/` covers `/o/eJxV` but `/url` isn't `/urls`.
+#[derive(Debug)]
+pub struct Rule {
+    pub provider: &'static str,
+    pub domain: &'static str,
+    pub path: &'static str,
+    pub action: Action,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        provider: "Mailchimp",
+        domain: "list-manage.com",
+        path: "/track/open.php",
+        action: Action::Beacon,
+    },
+    Rule {
+        provider: "SendGrid",
+        domain: "sendgrid.net",
+        path: "/wf/open",
+        action: Action::Beacon,
+    },
+    Rule {
+        provider: "Substack",
+        domain: "substack.com",
+        path: "/o/",
+        action: Action::Beacon,
+    },
+    Rule {
+        provider: "ConvertKit",
+        domain: "open.convertkit-mail.com",
+        path: "/",
+        action: Action::Beacon,
+    },
+    Rule {
+        provider: "HubSpot",
+        domain: "hubspotlinks.com",
+        path: "/Ctc/",
+        action: Action::Beacon,
+    },
+    Rule {
+        provider: "Google",
+        domain: "google.com",
+        path: "/url",
+        action: Action::Redirect { param: "q" },
+    },
+    Rule {
+        provider: "Facebook",
+        domain: "l.facebook.com",
+        path: "/l.php",
+        action: Action::Redirect { param: "u" },
+    },
+    Rule {
+        provider: "Outlook",
+        domain: "safelinks.protection.outlook.com",
+        path: "/",
+        action: Action::Redirect { param: "url" },
+    },
+    Rule {
+        provider: "Mailjet",
+        domain: "mjt.lu",
+        path: "/lnk/",
+        action: Action::Redirect { param: "url" },
+    },
+];
+
+impl Rule {
+    fn matches(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let on_domain = host == self.domain
+            || host
+                .strip_suffix(self.domain)
+                .map(|subdomain| subdomain.ends_with('.'))
+                .unwrap_or(false);
+
+        let on_path = url.path().strip_prefix(self.path).map(|rest| {
+            self.path.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+        });
+
+        on_domain && on_path.unwrap_or(false)
+    }
+}
+
+fn parse(url: &str) -> Option<Url> {
+    Url::parse(url.trim())
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Whether the image at `src` is a beacon as per the `rules`.
+fn is_beacon(src: &str, rules: &[Rule]) -> bool {
+    let url = match parse(src) {
+        Some(url) => url,
+        None => return false,
+    };
+
+    match rules.iter().find(|rule| {
+        matches!(rule.action, Action::Beacon) && rule.matches(&url)
+    }) {
+        Some(rule) => {
+            debug!("Dropping {} beacon", rule.provider);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Where the link to `href` ends up, if it's a redirect as per the `rules`.
+fn destination(href: &str, rules: &[Rule]) -> Option<String> {
+    let mut url = parse(href)?;
+    let mut redirected = false;
+
+    for _ in 0..MAX_REDIRECTS {
+        let param = rules.iter().find_map(|rule| match rule.action {
+            Action::Redirect { param } if rule.matches(&url) => {
+                debug!("Unwrapping {} redirect", rule.provider);
+                Some(param)
+            }
+            _ => None,
+        });
+        let target = param.and_then(|param| {
+            url.query_pairs()
+                .find(|(key, _)| key == param)
+                .and_then(|(_, target)| parse(&target))
+        });
+        match target {
+            Some(target) => {
+                url = target;
+                redirected = true;
+            }
+            None => break,
+        }
+    }
+
+    redirected.then(|| url.to_string())
+}
+
+/// Whether an image's `width` and `height` make it a single pixel at most.
+fn is_pixel(attributes: &[(String, String)]) -> bool {
+    let tiny = |name: &str| {
+        attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| {
+                matches!(value.trim().trim_end_matches("px"), "0" | "1")
+            })
+            .unwrap_or(false)
+    };
+
+    tiny("width") && tiny("height")
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", "\u{a0}")
+        .replace("&amp;", "&")
+}
+
+/// Splits a start tag (without its angle brackets) into its name and its
+/// attributes, as serialized by the sanitizer: lowercase and double quoted.
+pub fn parse_tag(tag: &str) -> (&str, Vec<(String, String)>) {
+    let (name, mut rest) = tag.split_once(' ').unwrap_or((tag, ""));
+    let mut attributes = vec![];
+
+    loop {
+        rest = rest.trim_start();
+        let Some((attribute, value)) = rest.split_once("=\"") else {
+            break;
+        };
+        let Some((value, after)) = value.split_once('"') else {
+            break;
+        };
+        attributes.push((attribute.trim().to_owned(), unescape(value)));
+        rest = after;
+    }
+
+    (name, attributes)
+}
+
+/// Where the start tag beginning at `html`'s `<` ends, past its `>`.
+pub fn tag_end(html: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in html.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Drops beacons and unwraps redirects from sanitized `html`.
+pub fn strip(html: &str, rules: &[Rule]) -> String {
+    let mut stripped = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        stripped.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match tag_end(rest) {
+            Some(end) => end,
+            None => break,
+        };
+        let (name, mut attributes) = parse_tag(&rest[1..end - 1]);
+        let attribute = |attributes: &[(String, String)], name: &str| {
+            attributes
+                .iter()
+                .position(|(attribute, _)| attribute == name)
+        };
+
+        match name {
+            "img" => {
+                let src = attribute(&attributes, "src")
+                    .map(|i| attributes[i].1.as_str())
+                    .unwrap_or("");
+                if is_beacon(src, rules) || is_pixel(&attributes) {
+                    rest = &rest[end..];
+                    continue;
+                }
+            }
+            "a" => {
+                let unwrapped = attribute(&attributes, "href").and_then(|i| {
+                    destination(&attributes[i].1, rules).map(|url| (i, url))
+                });
+                if let Some((i, url)) = unwrapped {
+                    attributes[i].1 = url;
+                    stripped.push_str("<a");
+                    for (attribute, value) in &attributes {
+                        stripped.push(' ');
+                        stripped.push_str(attribute);
+                        stripped.push_str("=\"");
+                        escape(value, &mut stripped);
+                        stripped.push('"');
+                    }
+                    stripped.push('>');
+                    rest = &rest[end..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+
+        stripped.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    stripped.push_str(rest);
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{strip, RULES};
+
+    fn img(src: &str) -> String {
+        format!(r#"<p>Hi<img src="{}" alt=""></p>"#, src)
+    }
+
+    fn link(href: &str) -> String {
+        format!(r#"<a href="{}" rel="noopener noreferrer">Read</a>"#, href)
+    }
+
+    #[test]
+    fn mailchimp_beacon() {
+        let html = img(concat!(
+            "https://news.us5.list-manage.com/track/open.php",
+            "?u=abc&amp;id=def&amp;e=123"
+        ));
+        assert_eq!(strip(&html, RULES), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn sendgrid_beacon() {
+        let html = img("https://u123.ct.sendgrid.net/wf/open?upn=xyz");
+        assert_eq!(strip(&html, RULES), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn substack_beacon() {
+        let html = img("https://email.mg1.substack.com/o/eJxVjk0OgyAQ");
+        assert_eq!(strip(&html, RULES), "<p>Hi</p>");
+        // Images hosted by Substack are fine
+        let html = img("https://substackcdn.com/image/fetch/w_1456/a.png");
+        assert_eq!(strip(&html, RULES), html);
+    }
+
+    #[test]
+    fn convertkit_beacon() {
+        let html = img("https://open.convertkit-mail.com/x0ulpr9v2vs4h");
+        assert_eq!(strip(&html, RULES), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn hubspot_beacon() {
+        let html = img("https://d2v8tf04.na1.hubspotlinks.com/Ctc/OQ+113/abc");
+        assert_eq!(strip(&html, RULES), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn google_redirect() {
+        let html = link(concat!(
+            "https://www.google.com/url?q=https://blog.example/post%3Fid%3D1",
+            "&amp;sa=D&amp;ust=1665"
+        ));
+        assert_eq!(strip(&html, RULES), link("https://blog.example/post?id=1"));
+    }
+
+    #[test]
+    fn facebook_redirect() {
+        let html = link(
+            "https://l.facebook.com/l.php?u=https%3A%2F%2Fshop.example%2F&amp;h=AT0",
+        );
+        assert_eq!(strip(&html, RULES), link("https://shop.example/"));
+    }
+
+    #[test]
+    fn outlook_redirect() {
+        let html = link(concat!(
+            "https://eur01.safelinks.protection.outlook.com/?url=",
+            "https%3A%2F%2Fnews.example%2Fa%26b%3D1&amp;data=05"
+        ));
+        assert_eq!(strip(&html, RULES), link("https://news.example/a&amp;b=1"));
+    }
+
+    #[test]
+    fn mailjet_redirect() {
+        let html =
+            link("https://x.mjt.lu/lnk/AUsA?url=https%3A%2F%2Fa.example");
+        assert_eq!(strip(&html, RULES), link("https://a.example/"));
+    }
+
+    #[test]
+    fn nested_redirects() {
+        let html = link(concat!(
+            "https://www.google.com/url?q=https%3A%2F%2Fl.facebook.com%2Fl.php",
+            "%3Fu%3Dhttps%253A%252F%252Fa.example%252Fx"
+        ));
+        assert_eq!(strip(&html, RULES), link("https://a.example/x"));
+    }
+
+    #[test]
+    fn unsafe_destinations_are_ignored() {
+        let html = link("https://www.google.com/url?q=javascript:alert(1)");
+        assert_eq!(strip(&html, RULES), html);
+        let html = link("https://www.google.com/search?q=https://a.example");
+        assert_eq!(strip(&html, RULES), html);
+    }
+
+    #[test]
+    fn paths_match_whole_segments() {
+        let html = link("https://www.google.com/urls?q=https://a.example");
+        assert_eq!(strip(&html, RULES), html);
+        let html =
+            link("https://www.google.com/url-shortener?q=https://a.example");
+        assert_eq!(strip(&html, RULES), html);
+        let html = img("https://u123.ct.sendgrid.net/wf/opened.png");
+        assert_eq!(strip(&html, RULES), html);
+    }
+
+    #[test]
+    fn tiny_images_are_beacons() {
+        let html =
+            r#"<img src="https://a.example/p.gif" width="1" height="1px">"#;
+        assert_eq!(strip(html, &[]), "");
+        let html =
+            r#"<img src="https://a.example/p.gif" width="1" height="50">"#;
+        assert_eq!(strip(html, &[]), html);
+    }
+
+    #[test]
+    fn everything_else_is_untouched() {
+        let html = concat!(
+            r#"<table width="600"><tbody><tr><td style="a:b">"#,
+            r#"<img src="https://cdn.example/hero.png" alt="1 &gt; 0">"#,
+            r#"<a href="https://news.example/?a=1&amp;b=2">x &lt; y</a>"#,
+            "</td></tr></tbody></table>",
+        );
+        assert_eq!(strip(html, RULES), html);
+    }
+}
@@ -9,7 +9,7 @@ use chrono::{DateTime, NaiveDateTime, Utc};
 /// let date_out = "2021-12-01T12:01:03+00:00";
 ///
 /// assert_eq!(
-///     sqlite_datetime_to_rfc3339(&date_in),
+///     sqlite_datetime_to_rfc3339(date_in),
 ///     date_out,
 ///     "A valid date wasn't parsed properly"
 /// );
@@ -42,11 +42,14 @@ impl From<i64> for Epoch {
     }
 }
 
-impl ToString for Epoch {
-    fn to_string(&self) -> String {
-        NaiveDateTime::from_timestamp(self.0, 0)
-            .format("%Y-%m-%d %H:%M:%S")
-            .to_string()
+impl std::fmt::Display for Epoch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            NaiveDateTime::from_timestamp(self.0, 0)
+                .format("%Y-%m-%d %H:%M:%S")
+        )
     }
 }
 
@@ -67,7 +70,7 @@ mod tests {
         let date_out = "2021-12-01T12:01:03+00:00";
 
         assert_eq!(
-            sqlite_datetime_to_rfc3339(&date_in),
+            sqlite_datetime_to_rfc3339(date_in),
             date_out,
             "A valid date wasn't parsed properly"
         );
@@ -93,7 +96,7 @@ mod tests {
         let date_in = "2021-13-01 12:01:03";
         let date_out = "2021-13-01T12:01:03+00:00";
 
-        assert_eq!(sqlite_datetime_to_rfc3339(&date_in), date_out);
+        assert_eq!(sqlite_datetime_to_rfc3339(date_in), date_out);
     }
 
     #[test]
@@ -103,6 +106,6 @@ mod tests {
         let date_in = "2021-12-01T12:01:03Z";
         let date_out = "2021-12-01T12:01:03+00:00";
 
-        assert_eq!(sqlite_datetime_to_rfc3339(&date_in), date_out);
+        assert_eq!(sqlite_datetime_to_rfc3339(date_in), date_out);
     }
 }
@@ -7,6 +7,7 @@ use axum::{
     routing::{get, post},
     Router,
 };
+use std::sync::Arc;
 use tower_http::{
     compression::CompressionLayer,
     trace::{
@@ -16,17 +17,37 @@ use tower_http::{
 };
 use tracing::Level;
 
+use crate::config::Config;
 use crate::database::Pool;
+use crate::web::unsubscribe::HttpOneClick;
 use crate::web::{handlers, serve_static};
 
-pub fn build_app(pool: Pool) -> axum::routing::IntoMakeService<Router> {
+pub fn build_app(
+    pool: Pool,
+    config: Arc<Config>,
+    one_click: HttpOneClick,
+) -> axum::routing::IntoMakeService<Router> {
     Router::new()
         .route("/", get(handlers::get_index))
         .route("/", post(handlers::create_feed))
         .route("/feeds/:reference", get(handlers::get_feed))
+        .route(
+            "/feeds/:reference/unsubscribe",
+            post(handlers::unsubscribe::<HttpOneClick>),
+        )
+        .route(
+            "/alternates/:reference/:entry",
+            get(handlers::get_entry_html),
+        )
+        .route(
+            "/attachments/:reference/:attachment/:filename",
+            get(handlers::get_attachment),
+        )
         .route("/:reference", get(serve_static::handler))
         .nest("/static", get(serve_static::handler))
         .layer(Extension(pool))
+        .layer(Extension(config))
+        .layer(Extension(one_click))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<Body>| {
@@ -1,27 +1,51 @@
 use askama::Template;
 use axum::{
     body,
-    extract::{Extension, Form, Path},
+    extract::{Extension, Form, Path, Query},
     http::{self, StatusCode},
     response::{IntoResponse, Redirect, Response},
 };
-use tracing::debug;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{debug, warn};
 
+use crate::config::Config;
 use crate::database::Pool;
-use crate::models::{Entry, Feed, FeedAtomTemplate, NewFeed};
-use crate::vars::{EMAIL_DOMAIN, WEB_URL};
+use crate::models::{
+    Attachment, ConfirmationsTemplate, Entry, Feed, FeedAtomTemplate, NewFeed,
+    Unsubscribe, UnsubscribeTemplate,
+};
+use crate::time::Epoch;
 use crate::web::errors::KtnError;
+use crate::web::unsubscribe::{one_click, OneClick};
+
+/// Subscription confirmations pinned to the top of a feed's page at most.
+const PINNED_CONFIRMATIONS: i64 = 3;
+
+/// Raster images, the only attachments shown inline or served as the type
+/// their sender declared. Anything else, an SVG or HTML passed off as an
+/// image, could run scripts on our origin if the browser rendered it.
+const INLINE_TYPES: &[&str] =
+    &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// The query parameters a feed can be narrowed down with.
+#[derive(Debug, Default, Deserialize)]
+pub struct FeedFilter {
+    /// Only the entries sent to the inbox's address with this `+tag`
+    pub tag: Option<String>,
+}
 
 pub async fn create_feed(
     form: Form<NewFeed>,
     Extension(pool): Extension<Pool>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> impl IntoResponse {
     println!("{:?}", form);
     let mut form = NewFeed {
         title: form.title.to_owned(),
         reference: form.reference.to_owned(),
     };
-    let redir: String = match form.save(&pool).await {
+    let redir: String = match form.save(&pool, &config).await {
         Ok(reference) => {
             format!("/feeds/{}.html", reference)
         }
@@ -33,14 +57,17 @@ pub async fn create_feed(
 
 pub async fn get_feed(
     Path(reference): Path<String>,
+    filter: Query<FeedFilter>,
     Extension(pool): Extension<Pool>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> Result<impl IntoResponse, KtnError> {
     match reference {
         rr if reference.ends_with(".html") => {
-            get_feed_html(Path(rr), Extension(pool)).await
+            get_feed_html(Path(rr), Extension(pool), Extension(config)).await
         }
         rr if reference.ends_with(".xml") => {
-            get_feed_xml(Path(rr), Extension(pool)).await
+            get_feed_xml(Path(rr), filter, Extension(pool), Extension(config))
+                .await
         }
         _ => Err(KtnError::NotFoundError),
     }
@@ -49,6 +76,7 @@ pub async fn get_feed(
 pub async fn get_feed_html(
     Path(reference): Path<String>,
     Extension(pool): Extension<Pool>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> Result<Response, KtnError> {
     let no_ext: &str = reference.split(".html").next().unwrap();
     let title = match Feed::get_title_given_reference(no_ext, &pool).await {
@@ -59,12 +87,48 @@ pub async fn get_feed_html(
         }
     };
 
+    let unsubscribe = match Unsubscribe::of_feed(no_ext, &pool).await {
+        Ok(unsubscribe) => unsubscribe.unwrap_or_default(),
+        Err(e) => {
+            debug!("Couldn't load unsubscribe link for \"{}\" ({})", no_ext, e);
+            return Err(KtnError::InternalServerError);
+        }
+    };
+
+    let confirmations =
+        match Entry::find_confirmations(no_ext, PINNED_CONFIRMATIONS, &pool)
+            .await
+        {
+            Ok(confirmations) => confirmations,
+            Err(e) => {
+                debug!(
+                    "Couldn't load confirmations for \"{}\" ({})",
+                    no_ext, e
+                );
+                return Err(KtnError::InternalServerError);
+            }
+        };
+
     let feed = NewFeed {
         reference: Some(no_ext.to_owned()),
         title,
     };
 
-    let template = feed.created_template().render();
+    let mut template = feed.created_template(&config);
+    if !confirmations.is_empty() {
+        template.confirmations = Some(ConfirmationsTemplate {
+            web_url: &config.web_url,
+            entries: &confirmations,
+        });
+    }
+    if !unsubscribe.is_empty() {
+        template.unsubscribe = Some(UnsubscribeTemplate {
+            web_url: &config.web_url,
+            reference: no_ext,
+            unsubscribe: &unsubscribe,
+        });
+    }
+    let template = template.render();
 
     match template {
         Ok(template) => Ok(Response::builder()
@@ -81,31 +145,57 @@ pub async fn get_feed_html(
 
 pub async fn get_feed_xml(
     Path(reference): Path<String>,
+    Query(filter): Query<FeedFilter>,
     Extension(pool): Extension<Pool>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> Result<Response, KtnError> {
     let no_ext: &str = reference.split(".xml").next().unwrap();
-    let entries = match Entry::find_by_reference(no_ext, &pool).await {
-        Ok(entries) => entries,
-        Err(_) => return Err(KtnError::NotFoundError),
-    };
+    // Tags are stored lowercased, as they come from email addresses
+    let tag = filter
+        .tag
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty());
+    let entries =
+        match Entry::find_by_reference(no_ext, tag.as_deref(), &pool).await {
+            Ok(entries) => entries,
+            Err(_) => return Err(KtnError::NotFoundError),
+        };
 
-    // Since we create "Sentinel" entries on Feed creation, this should never
-    // be reached, but just in case.
-    if entries.is_empty() {
+    // Since we create "Sentinel" entries on Feed creation, this should only
+    // be reached for tags nothing was sent to yet, but just in case.
+    if entries.is_empty()
+        && (tag.is_none()
+            || !matches!(Feed::feed_exists(no_ext, &pool).await, Ok(true)))
+    {
         return Err(KtnError::NotFoundError);
     }
+    let updated = match entries.first() {
+        Some(entry) => entry.created_at.clone(),
+        None => Epoch::from(0).to_string(),
+    };
 
     let title = match Feed::get_title_given_reference(no_ext, &pool).await {
         Ok(title) => title,
         _ => String::from("No feed title found"),
     };
 
+    let attachments = match Attachment::find_by_reference(no_ext, &pool).await {
+        Ok(attachments) => attachments,
+        Err(e) => {
+            debug!("Couldn't load attachments for \"{}\" ({})", no_ext, e);
+            return Err(KtnError::InternalServerError);
+        }
+    };
+
     let template = FeedAtomTemplate {
-        web_url: String::from(WEB_URL),
-        email_domain: String::from(EMAIL_DOMAIN),
+        web_url: config.web_url.clone(),
+        email_domain: config.email_domain.clone(),
         feed_title: title,
         feed_reference: no_ext.to_owned(),
+        feed_tag: tag,
+        updated,
         entries,
+        attachments,
     }
     .render();
 
@@ -124,7 +214,9 @@ pub async fn get_feed_xml(
     }
 }
 
-pub async fn get_index() -> impl IntoResponse {
+pub async fn get_index(
+    Extension(config): Extension<Arc<Config>>,
+) -> impl IntoResponse {
     #[derive(Template)]
     #[template(path = "index.html", ext = "html")]
     struct IndexTemplate {
@@ -132,7 +224,7 @@ pub async fn get_index() -> impl IntoResponse {
     }
 
     let template = IndexTemplate {
-        web_url: String::from(WEB_URL),
+        web_url: config.web_url.clone(),
     };
 
     Response::builder()
@@ -148,3 +240,266 @@ pub async fn get_index() -> impl IntoResponse {
         )))
         .unwrap()
 }
+
+/// Renders a single [`Entry`] as the HTML page the Atom feed links to as each
+/// entry's alternate. The newsletter's HTML is shown within a sandboxed
+/// `iframe` so its scripts and styles can't touch our page.
+pub async fn get_entry_html(
+    Path((reference, entry)): Path<(String, String)>,
+    Extension(pool): Extension<Pool>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<Response, KtnError> {
+    use crate::time::filters;
+
+    #[derive(Template)]
+    #[template(path = "alternate.html", ext = "html")]
+    struct AlternateTemplate {
+        pub web_url: String,
+        pub entry: Entry,
+    }
+
+    let id: i32 = match entry.strip_suffix(".html").map(str::parse) {
+        Some(Ok(id)) => id,
+        _ => return Err(KtnError::NotFoundError),
+    };
+
+    let entry = match Entry::find_by_id(&reference, id, &pool).await {
+        Ok(entry) => entry,
+        Err(_) => {
+            debug!("No Entry {} found for Feed \"{}\".", id, reference);
+            return Err(KtnError::NotFoundError);
+        }
+    };
+
+    let template = AlternateTemplate {
+        web_url: config.web_url.clone(),
+        entry,
+    }
+    .render();
+
+    match template {
+        Ok(template) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("text/html; charset=utf-8"),
+            )
+            .body(body::boxed(body::Full::from(template)))
+            .unwrap()),
+        _ => Err(KtnError::InternalServerError),
+    }
+}
+
+/// Serves a file attached to an [`Entry`] as a download. The filename in the
+/// path is only there for the reader's sake, the `id` is what's looked up.
+pub async fn get_attachment(
+    Path((reference, attachment, _filename)): Path<(String, String, String)>,
+    Extension(pool): Extension<Pool>,
+) -> Result<Response, KtnError> {
+    let id: i32 = match attachment.parse() {
+        Ok(id) => id,
+        _ => return Err(KtnError::NotFoundError),
+    };
+
+    let attachment = match Attachment::find_by_id(&reference, id, &pool).await {
+        Ok(attachment) => attachment,
+        Err(_) => {
+            debug!("No Attachment {} found for Feed \"{}\".", id, reference);
+            return Err(KtnError::NotFoundError);
+        }
+    };
+
+    let data = match attachment.data(&pool).await {
+        Ok(data) => data,
+        Err(_) => return Err(KtnError::InternalServerError),
+    };
+
+    let (content_type, disposition) = attachment_headers(&attachment);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        // Filenames only ever hold characters that are safe in here
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            format!("{}; filename=\"{}\"", disposition, attachment.filename),
+        )
+        .header(
+            http::header::CONTENT_SECURITY_POLICY,
+            http::HeaderValue::from_static("sandbox; default-src 'none'"),
+        )
+        .header(
+            http::header::X_CONTENT_TYPE_OPTIONS,
+            http::HeaderValue::from_static("nosniff"),
+        )
+        .body(body::boxed(body::Full::from(data)))
+        .unwrap())
+}
+
+/// The `Content-Type` and `Content-Disposition` an [`Attachment`] is served
+/// with: inline images are shown within the entry, as long as they're one of
+/// the [`INLINE_TYPES`], and everything else is downloaded.
+fn attachment_headers(attachment: &Attachment) -> (&str, &'static str) {
+    if !INLINE_TYPES.contains(&attachment.content_type.as_str()) {
+        return ("application/octet-stream", "attachment");
+    }
+
+    match attachment.content_id {
+        Some(_) => (&attachment.content_type, "inline"),
+        None => (&attachment.content_type, "attachment"),
+    }
+}
+
+/// Unsubscribes a [`Feed`] from its newsletter with an RFC 8058 one-click
+/// POST, showing the other ways to unsubscribe if that didn't work out.
+/// Feeds whose newsletter doesn't support it are sent back to their page,
+/// which links to those instead.
+pub async fn unsubscribe<C>(
+    Path(reference): Path<String>,
+    Extension(pool): Extension<Pool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(client): Extension<C>,
+) -> Result<Response, KtnError>
+where
+    C: OneClick + Clone + Send + Sync + 'static,
+{
+    #[derive(Template)]
+    #[template(path = "unsubscribed.html", ext = "html")]
+    struct UnsubscribedTemplate<'a> {
+        pub web_url: &'a str,
+        pub reference: &'a str,
+        pub title: String,
+        pub error: Option<String>,
+        pub links: UnsubscribeTemplate<'a>,
+    }
+
+    let title = match Feed::get_title_given_reference(&reference, &pool).await {
+        Ok(title) => title,
+        _ => {
+            debug!("No Feed with reference \"{}\" found.", reference);
+            return Err(KtnError::NotFoundError);
+        }
+    };
+
+    let unsubscribe = match Unsubscribe::of_feed(&reference, &pool).await {
+        Ok(Some(unsubscribe)) => unsubscribe,
+        Ok(None) => return Err(KtnError::NotFoundError),
+        Err(_) => return Err(KtnError::InternalServerError),
+    };
+
+    if !unsubscribe.one_click {
+        let page = format!("/feeds/{}.html", reference);
+        return Ok(Redirect::to(&page).into_response());
+    }
+
+    let error = one_click(&client, &unsubscribe).await.err();
+    if let Some(e) = &error {
+        warn!("Couldn't unsubscribe \"{}\" ({})", reference, e);
+    }
+
+    // Should it fail, the links are all that's left
+    let links = Unsubscribe {
+        one_click: false,
+        ..unsubscribe
+    };
+    let template = UnsubscribedTemplate {
+        web_url: &config.web_url,
+        reference: &reference,
+        title,
+        error,
+        links: UnsubscribeTemplate {
+            web_url: &config.web_url,
+            reference: &reference,
+            unsubscribe: &links,
+        },
+    }
+    .render();
+
+    match template {
+        Ok(template) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("text/html; charset=utf-8"),
+            )
+            .body(body::boxed(body::Full::from(template)))
+            .unwrap()),
+        _ => Err(KtnError::InternalServerError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::{Extension, Path};
+    use axum::http::header;
+
+    use super::get_attachment;
+    use crate::config::Config;
+    use crate::database::{connect, migrate};
+    use crate::models::{Entry, NewAttachment, NewFeed, Saved};
+
+    #[tokio::test]
+    async fn only_raster_images_are_served_inline() {
+        let pool = connect("sqlite::memory:", 1).await.unwrap();
+        migrate(&pool).await.unwrap();
+        let reference = NewFeed {
+            title: "Weekly".to_owned(),
+            reference: None,
+        }
+        .save(&pool, &Config::for_tests())
+        .await
+        .unwrap();
+        let entry_id = match Entry::for_tests(&reference, "Issue 1")
+            .save(&pool)
+            .await
+            .unwrap()
+        {
+            Saved::Inserted(id) => id,
+            saved => panic!("{:?}", saved),
+        };
+
+        let cases = [
+            ("logo.png", "image/png", "image/png", "inline"),
+            (
+                "logo.svg",
+                "image/svg+xml",
+                "application/octet-stream",
+                "attachment",
+            ),
+            (
+                "page.png",
+                "text/html",
+                "application/octet-stream",
+                "attachment",
+            ),
+        ];
+        for (filename, declared, content_type, disposition) in cases {
+            let mut attachment =
+                NewAttachment::new(filename, declared, b"<svg/>".to_vec());
+            attachment.content_id = Some(format!("{}@example.com", filename));
+            let id = attachment
+                .save(&reference, entry_id, &pool)
+                .await
+                .unwrap()
+                .id;
+
+            let response = get_attachment(
+                Path((reference.clone(), id.to_string(), filename.to_owned())),
+                Extension(pool.clone()),
+            )
+            .await
+            .unwrap();
+            let headers = response.headers();
+
+            assert_eq!(headers[header::CONTENT_TYPE], content_type);
+            assert_eq!(
+                headers[header::CONTENT_DISPOSITION],
+                format!("{}; filename=\"{}\"", disposition, filename)
+            );
+            assert_eq!(
+                headers[header::CONTENT_SECURITY_POLICY],
+                "sandbox; default-src 'none'"
+            );
+        }
+    }
+}
@@ -4,11 +4,15 @@
 //! * Homepage
 //! * Create feed
 //! * Render feed in XML
+//! * Render each feed entry as its own HTML page
+//! * Serve the files attached to entries
+//! * Unsubscribe feeds from their newsletter in one click
 //! * Serve static files (favicons, for now)
 
 mod app;
 mod errors;
 mod handlers;
 pub mod serve_static;
+pub mod unsubscribe;
 
 pub use app::build_app;
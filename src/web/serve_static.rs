@@ -1,8 +1,9 @@
 //! # Static file serving handler
 //!
 //! Usage:
-//! Setting `STATIC_FOLDER` in OS env or `.env` file such as
-//! `STATIC_FOLDER=name_of_folder`
+//! Setting `static_folder` in the [`Config`], e.g. through OS env or `.env`
+//! file such as `STATIC_FOLDER=name_of_folder`, and adding the [`Config`] as
+//! an [`Extension`] layer
 //! ```
 //! let app = Router::new().nest("/static", get(static))
 //! ```
@@ -11,23 +12,29 @@
 
 use axum::{
     body::{boxed, Body, BoxBody},
+    extract::Extension,
     http::{Request, Response, StatusCode, Uri},
 };
+use std::path::Path;
+use std::sync::Arc;
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
 
-use crate::vars::STATIC_FOLDER;
+use crate::config::Config;
 
 pub async fn handler(
     uri: Uri,
+    Extension(config): Extension<Arc<Config>>,
 ) -> Result<Response<BoxBody>, (StatusCode, String)> {
-    let res = get_static_file(uri.clone()).await?;
+    let res = get_static_file(uri.clone(), &config.static_folder).await?;
 
     if res.status() == StatusCode::NOT_FOUND {
         // try with `.html`
         // TODO: handle if the Uri has query parameters
         match format!("{}.html", uri).parse() {
-            Ok(uri_html) => get_static_file(uri_html).await,
+            Ok(uri_html) => {
+                get_static_file(uri_html, &config.static_folder).await
+            }
             Err(_) => Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Invalid URI".to_string(),
@@ -40,12 +47,13 @@ pub async fn handler(
 
 async fn get_static_file(
     uri: Uri,
+    folder: &Path,
 ) -> Result<Response<BoxBody>, (StatusCode, String)> {
     let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
 
     // `ServeDir` implements `tower::Service` so,
     // we can call it with `tower::ServiceExt::oneshot`
-    match ServeDir::new(STATIC_FOLDER).oneshot(req).await {
+    match ServeDir::new(folder).oneshot(req).await {
         Ok(res) => Ok(res.map(boxed)),
         Err(err) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
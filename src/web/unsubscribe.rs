@@ -0,0 +1,348 @@
+//! # One-click unsubscribing
+//!
+//! Newsletters that send a `List-Unsubscribe-Post` header along with an
+//! https `List-Unsubscribe` URL let anyone unsubscribe with a single POST to
+//! it (RFC 8058), no confirmation page in between, so the feed page does it
+//! on the reader's behalf. Who makes that request is a [`OneClick`], so
+//! tests don't go knocking on real newsletters' doors.
+//!
+//! The URL is whatever the sender put in the header, and anyone can have
+//! it requested, so only public addresses are ever connected to: nothing on
+//! the loopback, private or link-local networks the server can reach.
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::models::Unsubscribe;
+
+/// How long a newsletter gets to answer before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Makes the one-click unsubscribe requests.
+pub trait OneClick {
+    /// POSTs `List-Unsubscribe=One-Click` to `url`, explaining what went
+    /// wrong if it didn't succeed.
+    fn post(
+        &self,
+        url: &str,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// The [`OneClick`] that goes over the network.
+#[derive(Clone)]
+pub struct HttpOneClick {
+    client: reqwest::Client,
+    /// Whether non-global addresses are refused, only ever off in tests
+    global_only: bool,
+}
+
+impl HttpOneClick {
+    pub fn new() -> Result<Self, reqwest::Error> {
+        let client = HttpOneClick::builder()
+            .dns_resolver(Arc::new(GlobalResolver))
+            .build()?;
+
+        Ok(HttpOneClick {
+            client,
+            global_only: true,
+        })
+    }
+
+    /// An [`HttpOneClick`] that will connect anywhere, local test servers
+    /// included.
+    #[cfg(test)]
+    pub fn unrestricted() -> Result<Self, reqwest::Error> {
+        Ok(HttpOneClick {
+            client: HttpOneClick::builder().build()?,
+            global_only: false,
+        })
+    }
+
+    fn builder() -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            // RFC 8058 says redirects aren't to be followed
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(TIMEOUT)
+            .user_agent(concat!("ktn/", env!("CARGO_PKG_VERSION")))
+    }
+}
+
+impl OneClick for HttpOneClick {
+    async fn post(&self, url: &str) -> Result<(), String> {
+        // Hosts that are already an address never reach the resolver
+        let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let host = parsed.host_str().unwrap_or("");
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            if self.global_only && !is_global(ip) {
+                return Err(format!("{} isn't a public address", ip));
+            }
+        }
+
+        let response = self
+            .client
+            .post(url)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .map_err(describe)?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("the newsletter answered {}", status)),
+        }
+    }
+}
+
+/// What went wrong with a request, down to its root cause, as reqwest's own
+/// message hardly ever says.
+fn describe(e: reqwest::Error) -> String {
+    let mut description = e.to_string();
+    let mut source = std::error::Error::source(&e);
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+
+    description
+}
+
+/// Resolves host names to their public addresses only, failing when there
+/// are none, so they can't point the request at our own network.
+struct GlobalResolver;
+
+impl reqwest::dns::Resolve for GlobalResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|address| is_global(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+
+            let addresses: reqwest::dns::Addrs =
+                Box::new(addresses.into_iter());
+            Ok(addresses)
+        })
+    }
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to being
+/// loopback, private, link-local, shared, reserved or otherwise special.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => {
+            // Mapped and NAT64 addresses are IPv4 ones in disguise
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_global_v4(v4);
+            }
+            let segments = ip.segments();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_global_v4(Ipv4Addr::new(a, b, c, d));
+            }
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || segments[0] & 0xfe00 == 0xfc00
+                // Link-local, fe80::/10
+                || segments[0] & 0xffc0 == 0xfe80
+                // Documentation, 2001:db8::/32
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", 0.0.0.0/8
+        || a == 0
+        // Shared address space, 100.64.0.0/10
+        || (a == 100 && b & 0xc0 == 64)
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && b & 0xfe == 18)
+        // Reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+/// Unsubscribes with a single POST if the newsletter allows it.
+pub async fn one_click<C: OneClick>(
+    client: &C,
+    unsubscribe: &Unsubscribe,
+) -> Result<(), String> {
+    match (&unsubscribe.url, unsubscribe.one_click) {
+        (Some(url), true) => client.post(url).await,
+        _ => Err("this newsletter doesn't support one-click".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_global, one_click, HttpOneClick, OneClick};
+    use crate::models::Unsubscribe;
+    use axum::{extract::Extension, routing::post, Router};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Remembers the URLs it's asked to POST to, answering with `result`.
+    #[derive(Default)]
+    struct MockOneClick {
+        posted: Mutex<Vec<String>>,
+        result: Option<String>,
+    }
+
+    impl OneClick for MockOneClick {
+        async fn post(&self, url: &str) -> Result<(), String> {
+            self.posted.lock().unwrap().push(url.to_owned());
+            match &self.result {
+                Some(e) => Err(e.clone()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn unsubscribe(one_click: bool) -> Unsubscribe {
+        Unsubscribe {
+            url: Some("https://news.example/unsub?u=1".to_owned()),
+            mailto: Some("mailto:unsub@news.example".to_owned()),
+            one_click,
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_when_allowed() {
+        let client = MockOneClick::default();
+
+        assert!(one_click(&client, &unsubscribe(true)).await.is_ok());
+        assert_eq!(
+            *client.posted.lock().unwrap(),
+            ["https://news.example/unsub?u=1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn doesnt_post_otherwise() {
+        let client = MockOneClick::default();
+
+        assert!(one_click(&client, &unsubscribe(false)).await.is_err());
+        assert!(one_click(&client, &Unsubscribe::default()).await.is_err());
+        assert!(client.posted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported() {
+        let client = MockOneClick {
+            result: Some("the newsletter answered 500".to_owned()),
+            ..MockOneClick::default()
+        };
+
+        assert_eq!(
+            one_click(&client, &unsubscribe(true)).await,
+            Err("the newsletter answered 500".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn http_request() {
+        let received: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let app = Router::new()
+            .route(
+                "/unsub",
+                post(
+                    |Extension(received): Extension<
+                        Arc<Mutex<Option<String>>>,
+                    >,
+                     body: String| async move {
+                        *received.lock().unwrap() = Some(body);
+                    },
+                ),
+            )
+            .layer(Extension(received.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let client = HttpOneClick::unrestricted().unwrap();
+        client
+            .post(&format!("http://{}/unsub", address))
+            .await
+            .unwrap();
+        assert_eq!(
+            received.lock().unwrap().as_deref(),
+            Some("List-Unsubscribe=One-Click")
+        );
+
+        let missing = client.post(&format!("http://{}/gone", address)).await;
+        assert_eq!(
+            missing,
+            Err("the newsletter answered 404 Not Found".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn local_addresses_are_refused() {
+        let client = HttpOneClick::new().unwrap();
+
+        for url in [
+            "https://127.0.0.1/unsub",
+            "https://[::1]/unsub",
+            "https://169.254.169.254/latest/meta-data",
+            "https://localhost/unsub",
+        ] {
+            let refused = client.post(url).await.unwrap_err();
+            assert!(refused.contains("public address"), "{}", refused);
+        }
+    }
+
+    #[test]
+    fn global_addresses() {
+        let global = |ip: &str| is_global(ip.parse().unwrap());
+
+        assert!(global("93.184.216.34"));
+        assert!(global("2606:2800:220:1:248:1893:25c8:1946"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!global(ip), "{}", ip);
+        }
+    }
+}